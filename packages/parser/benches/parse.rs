@@ -0,0 +1,38 @@
+//! Compares the owned and the borrowed parser on a generated config with
+//! thousands of `server` blocks. Run with `cargo bench -p parser`.
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use std::fmt::Write;
+
+fn synthetic_config(servers: usize) -> String {
+    let mut config = String::from("# generated\nhttp {\n");
+    for i in 0..servers {
+        write!(
+            config,
+            "    server {{\n        server_name \"site{i}.example.com\" www.site{i}.example.com;\n        \
+             listen 10.0.{}.{}:80;\n        root '/srv/www/site{i}';\n        \
+             location / {{\n            index index.html;\n        }}\n    }}\n",
+            i / 256,
+            i % 256,
+            i = i
+        )
+        .unwrap();
+    }
+    config.push_str("}\n");
+    config
+}
+
+fn parse(c: &mut Criterion) {
+    let config = synthetic_config(5000);
+    let mut group = c.benchmark_group("parse 5000 servers");
+    group.throughput(Throughput::Bytes(config.len() as u64));
+    group.bench_function("owned", |b| {
+        b.iter(|| parser::parse(black_box(&config)).unwrap())
+    });
+    group.bench_function("borrowed", |b| {
+        b.iter(|| parser::borrowed::parse(black_box(&config)).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
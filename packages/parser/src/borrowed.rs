@@ -0,0 +1,120 @@
+//! An AST that borrows from the parsed input instead of allocating a
+//! `String` per name and parameter. Names and comments are always slices of
+//! the input, parameter values only own their text when escapes or CRLF line
+//! breaks had to be replaced. [`crate::parse`] is this parser followed by
+//! [`Block::into_owned`].
+use crate::{ParameterType, ParseError, Parser, Span};
+use std::borrow::Cow;
+
+#[derive(Debug, PartialEq)]
+pub struct Parameter<'a> {
+    pub parameter_type: ParameterType,
+    pub value: Cow<'a, str>,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub struct Directive<'a> {
+    pub name: &'a str,
+    pub parameters: Vec<Parameter<'a>>,
+    pub block: Option<Block<'a>>,
+    pub span: Span,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Comment<'a> {
+    pub text: &'a str,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub struct Block<'a> {
+    pub directives: Vec<Directive<'a>>,
+    pub comments: Vec<Comment<'a>>,
+    pub span: Span,
+}
+
+impl Parameter<'_> {
+    pub fn into_owned(self) -> crate::Parameter {
+        crate::Parameter {
+            parameter_type: self.parameter_type,
+            value: self.value.into_owned(),
+            span: self.span,
+        }
+    }
+}
+
+impl Directive<'_> {
+    pub fn into_owned(self) -> crate::Directive {
+        crate::Directive {
+            name: self.name.to_string(),
+            parameters: self
+                .parameters
+                .into_iter()
+                .map(Parameter::into_owned)
+                .collect(),
+            block: self.block.map(Block::into_owned),
+            span: self.span,
+        }
+    }
+}
+
+impl Comment<'_> {
+    pub fn into_owned(self) -> crate::Comment {
+        crate::Comment {
+            text: self.text.to_string(),
+            span: self.span,
+        }
+    }
+}
+
+impl Block<'_> {
+    pub fn into_owned(self) -> crate::Block {
+        crate::Block {
+            directives: self
+                .directives
+                .into_iter()
+                .map(Directive::into_owned)
+                .collect(),
+            comments: self.comments.into_iter().map(Comment::into_owned).collect(),
+            span: self.span,
+        }
+    }
+}
+
+pub fn parse(input: &str) -> Result<Block<'_>, ParseError> {
+    let mut parser = Parser::new(input, None);
+    let start = parser.position();
+    parser.parse_block(start, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn borrows_from_input() {
+        let source = "server { listen 80; root '/srv/a\\'b'; } # done";
+        let block = parse(source).unwrap();
+        let server = &block.directives[0];
+        assert_eq!(server.name, "server");
+        let inner = server.block.as_ref().unwrap();
+        let listen = &inner.directives[0].parameters[0];
+        assert!(matches!(listen.value, Cow::Borrowed("80")));
+        let root = &inner.directives[1].parameters[0];
+        assert!(matches!(&root.value, Cow::Owned(value) if value == "/srv/a'b"));
+        assert_eq!(block.comments[0].text, " done");
+    }
+
+    #[test]
+    fn owned_matches_borrowed() {
+        let source = "http {\n  # c\n  server { listen 'a b' 80; }\n}\n";
+        let owned = parse(source).unwrap().into_owned();
+        assert_eq!(owned.to_string(), crate::parse(source).unwrap().to_string());
+        let http = owned.directives[0].block.as_ref().unwrap();
+        assert_eq!(http.comments[0].text, " c");
+        let listen = &http.directives[0].block.as_ref().unwrap().directives[0];
+        assert_eq!(listen.parameters[0].value, "a b");
+        assert_eq!(&source[listen.parameters[0].span.range()], "'a b'");
+    }
+}
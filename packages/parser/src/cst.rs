@@ -0,0 +1,472 @@
+//! A lossless concrete syntax tree. Unlike [`Block`](crate::Block) it keeps
+//! whitespace, comments and the exact source text of every parameter, so
+//! printing a parsed tree gives back the input byte for byte and edits only
+//! touch the parts that changed.
+use crate::{unescape, writer::quote, ParameterType, ParseError, ParseErrorKind, Parser, Position};
+use std::fmt;
+
+const INDENT: &str = "    ";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trivia {
+    Whitespace(String),
+    /// A comment including its `#`, without the line break.
+    Comment(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    Trivia(Trivia),
+    Directive(CstDirective),
+}
+
+/// The nodes of a file, or the nodes between the braces of a block.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CstBlock {
+    pub nodes: Vec<Node>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CstDirective {
+    pub name: String,
+    pub parameters: Vec<CstParameter>,
+    /// Whitespace and comments between the last parameter and `;` or `{`.
+    pub trailing: Vec<Trivia>,
+    pub block: Option<CstBlock>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CstParameter {
+    /// Whitespace and comments between the previous token and this one.
+    pub leading: Vec<Trivia>,
+    pub parameter_type: ParameterType,
+    /// The source text, including quotes and escapes.
+    pub raw: String,
+}
+
+impl CstParameter {
+    /// A parameter preceded by a single space, quoted only if `value` needs it.
+    pub fn new(value: &str) -> Self {
+        let (parameter_type, raw) = quote(value, ParameterType::Simple);
+        Self {
+            leading: vec![Trivia::Whitespace(" ".to_string())],
+            parameter_type,
+            raw,
+        }
+    }
+
+    /// The value with quotes removed and escapes resolved.
+    pub fn value(&self) -> String {
+        match self.parameter_type {
+            ParameterType::Simple => unescape(&self.raw).into_owned(),
+            _ => unescape(&self.raw[1..self.raw.len() - 1]).into_owned(),
+        }
+    }
+
+    /// Replaces the value, keeping the quote style unless an unquoted
+    /// parameter can't hold the new value.
+    pub fn set_value(&mut self, value: &str) {
+        let (parameter_type, raw) = quote(value, self.parameter_type);
+        self.parameter_type = parameter_type;
+        self.raw = raw;
+    }
+}
+
+impl CstDirective {
+    /// A directive terminated by `;`, e.g. `CstDirective::new("listen", &["80"])`.
+    pub fn new(name: &str, values: &[&str]) -> Self {
+        Self {
+            name: name.to_string(),
+            parameters: values.iter().map(|v| CstParameter::new(v)).collect(),
+            trailing: Vec::new(),
+            block: None,
+        }
+    }
+
+    /// A directive with a block, e.g. `server { ... }`.
+    pub fn with_block(name: &str, values: &[&str], block: CstBlock) -> Self {
+        Self {
+            trailing: vec![Trivia::Whitespace(" ".to_string())],
+            block: Some(block),
+            ..Self::new(name, values)
+        }
+    }
+}
+
+impl CstBlock {
+    pub fn directives(&self) -> impl Iterator<Item = &CstDirective> {
+        self.nodes.iter().filter_map(|node| match node {
+            Node::Directive(directive) => Some(directive),
+            Node::Trivia(_) => None,
+        })
+    }
+
+    pub fn directives_mut(&mut self) -> impl Iterator<Item = &mut CstDirective> {
+        self.nodes.iter_mut().filter_map(|node| match node {
+            Node::Directive(directive) => Some(directive),
+            Node::Trivia(_) => None,
+        })
+    }
+
+    /// Adds `directive` after the last directive of the block, on its own
+    /// line and with the same indentation. Run [`CstBlock::format`] for a
+    /// consistent layout of blocks that had no directives yet.
+    pub fn push(&mut self, directive: CstDirective) {
+        let last = self
+            .nodes
+            .iter()
+            .rposition(|node| matches!(node, Node::Directive(_)));
+        let (index, indent) = match last {
+            Some(last) => {
+                let indent = match last.checked_sub(1).map(|i| &self.nodes[i]) {
+                    Some(Node::Trivia(Trivia::Whitespace(ws))) => {
+                        ws.rsplit('\n').next().unwrap_or("").to_string()
+                    }
+                    _ => String::new(),
+                };
+                (last + 1, indent)
+            }
+            None => (self.nodes.len(), String::new()),
+        };
+        let whitespace = Trivia::Whitespace(format!("\n{}", indent));
+        self.nodes.insert(index, Node::Trivia(whitespace));
+        self.nodes.insert(index + 1, Node::Directive(directive));
+    }
+
+    /// Prints the block with one directive per line, four spaces of
+    /// indentation per level, single spaces between parameters and at most
+    /// one blank line in a row. Comments and quote styles are kept; comments
+    /// between parameters move to the line above their directive.
+    pub fn format(&self) -> String {
+        let mut formatter = Formatter {
+            output: String::new(),
+            line_open: false,
+        };
+        formatter.block(self, 0);
+        formatter.close_line();
+        formatter.output
+    }
+}
+
+struct Formatter {
+    output: String,
+    /// Whether the last written line still lacks its line break, so a
+    /// comment on the same source line can follow it.
+    line_open: bool,
+}
+
+impl Formatter {
+    fn close_line(&mut self) {
+        if self.line_open {
+            self.output.push('\n');
+            self.line_open = false;
+        }
+    }
+
+    fn line(&mut self, depth: usize, text: &str) {
+        self.close_line();
+        self.output.push_str(&INDENT.repeat(depth));
+        self.output.push_str(text);
+        self.line_open = true;
+    }
+
+    fn block(&mut self, block: &CstBlock, depth: usize) {
+        let mut newlines = 0;
+        let mut first = true;
+        for node in &block.nodes {
+            match node {
+                Node::Trivia(Trivia::Whitespace(ws)) => {
+                    newlines += ws.matches('\n').count();
+                    continue;
+                }
+                Node::Trivia(Trivia::Comment(comment)) if newlines == 0 && self.line_open => {
+                    self.output.push(' ');
+                    self.output.push_str(comment.trim_end());
+                }
+                node => {
+                    if newlines > 1 && !first {
+                        self.close_line();
+                        self.output.push('\n');
+                    }
+                    match node {
+                        Node::Trivia(Trivia::Comment(comment)) => {
+                            self.line(depth, comment.trim_end())
+                        }
+                        Node::Directive(directive) => self.directive(directive, depth),
+                        Node::Trivia(Trivia::Whitespace(_)) => unreachable!(),
+                    }
+                }
+            }
+            newlines = 0;
+            first = false;
+        }
+    }
+
+    fn directive(&mut self, directive: &CstDirective, depth: usize) {
+        let comments = directive
+            .parameters
+            .iter()
+            .flat_map(|p| &p.leading)
+            .chain(&directive.trailing);
+        for trivia in comments {
+            if let Trivia::Comment(comment) = trivia {
+                self.line(depth, comment.trim_end());
+            }
+        }
+        let mut text = directive.name.clone();
+        for parameter in &directive.parameters {
+            text.push(' ');
+            text.push_str(&parameter.raw);
+        }
+        match &directive.block {
+            None => {
+                text.push(';');
+                self.line(depth, &text);
+            }
+            Some(block) => {
+                text.push_str(" {");
+                self.line(depth, &text);
+                self.block(block, depth + 1);
+                self.line(depth, "}");
+            }
+        }
+    }
+}
+
+fn write_trivia(f: &mut fmt::Formatter<'_>, trivia: &[Trivia]) -> fmt::Result {
+    for trivia in trivia {
+        match trivia {
+            Trivia::Whitespace(text) | Trivia::Comment(text) => f.write_str(text)?,
+        }
+    }
+    Ok(())
+}
+
+/// Prints the tree exactly as it was parsed, including any edits.
+impl fmt::Display for CstBlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for node in &self.nodes {
+            match node {
+                Node::Trivia(trivia) => write_trivia(f, std::slice::from_ref(trivia))?,
+                Node::Directive(directive) => write!(f, "{}", directive)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for CstDirective {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)?;
+        for parameter in &self.parameters {
+            write_trivia(f, &parameter.leading)?;
+            f.write_str(&parameter.raw)?;
+        }
+        write_trivia(f, &self.trailing)?;
+        match &self.block {
+            None => f.write_str(";"),
+            Some(block) => write!(f, "{{{}}}", block),
+        }
+    }
+}
+
+impl Parser<'_> {
+    fn parse_trivia(&mut self) -> Vec<Trivia> {
+        let mut trivia = Vec::new();
+        loop {
+            let start = self.offset;
+            match self.peek() {
+                Some('#') => {
+                    let comment = self.parse_comment();
+                    let text = &self.input[comment.span.range()];
+                    trivia.push(Trivia::Comment(text.to_string()));
+                }
+                Some(char) if char.is_whitespace() => {
+                    while matches!(self.peek(), Some(c) if c.is_whitespace()) {
+                        self.bump();
+                    }
+                    trivia.push(Trivia::Whitespace(
+                        self.input[start..self.offset].to_string(),
+                    ));
+                }
+                _ => return trivia,
+            }
+        }
+    }
+
+    fn parse_cst_block(&mut self, start: Position, nested: bool) -> Result<CstBlock, ParseError> {
+        let mut nodes = Vec::new();
+        loop {
+            nodes.extend(self.parse_trivia().into_iter().map(Node::Trivia));
+            match self.peek() {
+                None if nested => {
+                    return Err(ParseError::new(
+                        ParseErrorKind::UnclosedBlock,
+                        start,
+                        self.file.clone(),
+                    ))
+                }
+                None => break,
+                Some('}') if nested => {
+                    self.bump();
+                    break;
+                }
+                Some('}') => return Err(self.error(ParseErrorKind::UnexpectedClosingBrace)),
+                Some(_) => nodes.push(Node::Directive(self.parse_cst_directive()?)),
+            }
+        }
+        Ok(CstBlock { nodes })
+    }
+
+    fn parse_cst_directive(&mut self) -> Result<CstDirective, ParseError> {
+        let name = match self.peek() {
+            Some(char) if char.is_numeric() || crate::is_special(char) => {
+                return Err(self.error(ParseErrorKind::InvalidDirectiveName(char)))
+            }
+            _ => self.parse_simple().to_string(),
+        };
+        let mut parameters = Vec::new();
+        loop {
+            let trivia = self.parse_trivia();
+            match self.peek() {
+                None => return Err(self.error(ParseErrorKind::UnexpectedEof)),
+                Some(';') => {
+                    self.bump();
+                    return Ok(CstDirective {
+                        name,
+                        parameters,
+                        trailing: trivia,
+                        block: None,
+                    });
+                }
+                Some('{') => {
+                    let block_start = self.position();
+                    self.bump();
+                    return Ok(CstDirective {
+                        name,
+                        parameters,
+                        trailing: trivia,
+                        block: Some(self.parse_cst_block(block_start, true)?),
+                    });
+                }
+                Some('}') => return Err(self.error(ParseErrorKind::UnexpectedCharacter('}'))),
+                Some(_) => {
+                    let start = self.offset;
+                    let parameter = self.parse_parameter()?;
+                    parameters.push(CstParameter {
+                        leading: trivia,
+                        parameter_type: parameter.parameter_type,
+                        raw: self.input[start..self.offset].to_string(),
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Parses `input` into a lossless tree. Accepts and rejects the same inputs
+/// as [`parse`](crate::parse).
+pub fn parse(input: &str) -> Result<CstBlock, ParseError> {
+    let mut parser = Parser::new(input, None);
+    let start = parser.position();
+    parser.parse_cst_block(start, false)
+}
+
+/// Parses and re-prints `input` with [`CstBlock::format`].
+pub fn format(input: &str) -> Result<String, ParseError> {
+    parse(input).map(|block| block.format())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSY: &str = "# global settings\r\nhttp{\n\tserver   {   # main site\n      \
+                         server_name 'example.com'  \"www.example.com\" ;\n\n\n   listen \
+                         80 # plain\n  81;\n}\n\n  server{listen 82;}}\n# eof\n";
+
+    #[test]
+    fn round_trip() {
+        for source in &[
+            MESSY,
+            "",
+            "  \n# only a comment",
+            "a \"esc\\\"aped\" 'x\\\\' b\\;c;",
+            "a 'multi\r\nline';\r\n",
+        ] {
+            assert_eq!(parse(source).unwrap().to_string(), *source);
+        }
+    }
+
+    #[test]
+    fn same_errors_as_ast_parser() {
+        for source in &["a {", "a 'b", "}", "1a;", "a b }", "a"] {
+            assert_eq!(
+                parse(source).unwrap_err(),
+                crate::parse(source).unwrap_err()
+            );
+        }
+    }
+
+    #[test]
+    fn format() {
+        let expected = "# global settings\nhttp {\n    server { # main site\n        \
+                        server_name 'example.com' \"www.example.com\";\n\n        \
+                        # plain\n        listen 80 81;\n    }\n\n    server {\n        \
+                        listen 82;\n    }\n}\n# eof\n";
+        assert_eq!(super::format(MESSY).unwrap(), expected);
+        assert_eq!(super::format(expected).unwrap(), expected);
+    }
+
+    #[test]
+    fn edit_keeps_formatting() {
+        let source = "http {\n    server {\n        listen 80; # public\n    }\n}\n";
+        let mut root = parse(source).unwrap();
+        let http = root.directives_mut().next().unwrap();
+        let http = http.block.as_mut().unwrap();
+        let server = http
+            .directives_mut()
+            .next()
+            .unwrap()
+            .block
+            .as_mut()
+            .unwrap();
+        let listen = server.directives_mut().next().unwrap();
+        listen.parameters[0].set_value("8080");
+
+        let mut new_server = CstBlock::default();
+        new_server.push(CstDirective::new("server_name", &["b.example.com"]));
+        new_server.push(CstDirective::new("root", &["/var/www/my site"]));
+        http.push(CstDirective::with_block("server", &[], new_server));
+
+        assert_eq!(
+            root.to_string(),
+            "http {\n    server {\n        listen 8080; # public\n    }\n    server {\n\
+             server_name b.example.com;\nroot \"/var/www/my site\";}\n}\n"
+        );
+        assert_eq!(
+            root.format(),
+            "http {\n    server {\n        listen 8080; # public\n    }\n    server {\n        \
+             server_name b.example.com;\n        root \"/var/www/my site\";\n    }\n}\n"
+        );
+    }
+
+    #[test]
+    fn set_value_keeps_quote_style() {
+        let mut root = parse("a 'x' \"y\" z;").unwrap();
+        let directive = root.directives_mut().next().unwrap();
+        directive.parameters[0].set_value("it's");
+        directive.parameters[1].set_value("plain");
+        directive.parameters[2].set_value("two words");
+        assert_eq!(root.to_string(), "a 'it\\'s' \"plain\" \"two words\";");
+        let values: Vec<_> = root
+            .directives()
+            .next()
+            .unwrap()
+            .parameters
+            .iter()
+            .map(|p| p.value())
+            .collect();
+        assert_eq!(values, vec!["it's", "plain", "two words"]);
+    }
+}
@@ -0,0 +1,229 @@
+use crate::{parse_with_file, Block, Directive, ParseError, ParseErrorKind, Position, Span};
+use glob::Pattern;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// Parses the config file at `path` and replaces every `include pattern;`
+/// with the directives of the files it matches.
+///
+/// Relative patterns are resolved against the directory of the file that
+/// contains the `include`, and matches are spliced in alphabetical order.
+/// A pattern without wildcards must name an existing file, a glob may match
+/// nothing. Spans of included directives point into the included files.
+pub fn parse_file(path: &Path) -> Result<Block, ParseError> {
+    load(path, None, &mut Vec::new())
+}
+
+/// `stack` holds the canonical paths of the files currently being loaded.
+fn load(
+    path: &Path,
+    included_from: Option<&Span>,
+    stack: &mut Vec<PathBuf>,
+) -> Result<Block, ParseError> {
+    let io_error = |e: io::Error| {
+        let kind = ParseErrorKind::Io(format!("could not read {}: {}", path.display(), e));
+        match included_from {
+            Some(span) => ParseError::at_span(kind, span),
+            None => ParseError::new(
+                kind,
+                Position {
+                    offset: 0,
+                    line: 1,
+                    column: 1,
+                },
+                Some(Arc::new(path.to_path_buf())),
+            ),
+        }
+    };
+    let canonical = fs::canonicalize(path).map_err(io_error)?;
+    if let (true, Some(span)) = (stack.contains(&canonical), included_from) {
+        return Err(ParseError::at_span(
+            ParseErrorKind::IncludeCycle(path.to_path_buf()),
+            span,
+        ));
+    }
+    let source = fs::read_to_string(path).map_err(io_error)?;
+    // errors in this file keep the text that was parsed, for their snippet
+    let with_input = |mut error: ParseError| {
+        if error.input.is_none() && error.file.as_deref().map(PathBuf::as_path) == Some(path) {
+            error.input = Some(Arc::from(&source[..]));
+        }
+        error
+    };
+    let mut block = parse_with_file(&source, path).map_err(with_input)?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    stack.push(canonical);
+    let result = expand(&mut block, dir, stack).map_err(with_input);
+    stack.pop();
+    result.map(|_| block)
+}
+
+/// Comments of an included file take the place of the `include` among the
+/// comments of `block`, so they stay in source order.
+fn expand(block: &mut Block, dir: &Path, stack: &mut Vec<PathBuf>) -> Result<(), ParseError> {
+    let mut comments = std::mem::take(&mut block.comments).into_iter().peekable();
+    for mut directive in std::mem::take(&mut block.directives) {
+        if directive.name != "include" {
+            if let Some(inner) = &mut directive.block {
+                expand(inner, dir, stack)?;
+            }
+            block.directives.push(directive);
+            continue;
+        }
+        while let Some(comment) =
+            comments.next_if(|comment| comment.span.start.offset < directive.span.start.offset)
+        {
+            block.comments.push(comment);
+        }
+        for path in resolve(&directive, dir)? {
+            let included = load(&path, Some(&directive.span), stack)?;
+            block.directives.extend(included.directives);
+            block.comments.extend(included.comments);
+        }
+    }
+    block.comments.extend(comments);
+    Ok(())
+}
+
+fn resolve(directive: &Directive, dir: &Path) -> Result<Vec<PathBuf>, ParseError> {
+    let invalid = |message: String| {
+        ParseError::at_span(ParseErrorKind::InvalidInclude(message), &directive.span)
+    };
+    if directive.block.is_some() {
+        return Err(invalid("include can't have a block".to_string()));
+    }
+    let value = match &directive.parameters[..] {
+        [parameter] => &parameter.value,
+        _ => return Err(invalid("expected exactly one path".to_string())),
+    };
+    if !value.contains(&['*', '?', '['][..]) {
+        return Ok(vec![dir.join(value)]);
+    }
+    let pattern = if Path::new(value).is_absolute() || dir.as_os_str().is_empty() {
+        value.clone()
+    } else {
+        format!("{}/{}", Pattern::escape(&dir.to_string_lossy()), value)
+    };
+    let mut paths = Vec::new();
+    for entry in glob::glob(&pattern).map_err(|e| invalid(e.msg.to_string()))? {
+        let path = entry
+            .map_err(|e| ParseError::at_span(ParseErrorKind::Io(e.to_string()), &directive.span))?;
+        if path.is_file() {
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory under the system temp dir, removed on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("paykan-parser-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        fn write(&self, name: &str, content: &str) -> PathBuf {
+            let path = self.0.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, content).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn names(block: &Block) -> Vec<&str> {
+        block.directives.iter().map(|d| d.name.as_str()).collect()
+    }
+
+    #[test]
+    fn splices_globbed_files_in_place() {
+        let dir = TempDir::new("glob");
+        let root = dir.write(
+            "paykan.conf",
+            "http {\n    # before\n    first;\n    include sites/*.conf;\n    # after\n    last;\n}\n",
+        );
+        dir.write("sites/b.conf", "server { listen 81; }\n");
+        dir.write(
+            "sites/a.conf",
+            "# a\nserver { listen 80; include ../extra; }\n",
+        );
+        dir.write("sites/ignored.txt", "broken {");
+        dir.write("extra", "server_name a;");
+
+        let block = parse_file(&root).unwrap();
+        let http = block.directives[0].block.as_ref().unwrap();
+        assert_eq!(names(http), vec!["first", "server", "server", "last"]);
+        let comments: Vec<_> = http.comments.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(comments, vec![" before", " a", " after"]);
+
+        let a = http.directives[1].block.as_ref().unwrap();
+        assert_eq!(names(a), vec!["listen", "server_name"]);
+        let file = a.directives[1].span.file.as_ref().unwrap();
+        assert!(file.ends_with("extra"));
+        let file = http.directives[2].span.file.as_ref().unwrap();
+        assert!(file.ends_with("sites/b.conf"));
+        assert_eq!(http.directives[3].span.file.as_deref(), Some(&root));
+    }
+
+    #[test]
+    fn empty_glob_and_missing_file() {
+        let dir = TempDir::new("missing");
+        let root = dir.write("paykan.conf", "include none/*.conf;\na;");
+        assert_eq!(names(&parse_file(&root).unwrap()), vec!["a"]);
+
+        let root = dir.write("paykan.conf", "a;\ninclude none.conf;");
+        let error = parse_file(&root).unwrap_err();
+        assert!(matches!(error.kind, ParseErrorKind::Io(_)));
+        assert_eq!((error.line, error.column), (2, 1));
+        assert_eq!(error.file.as_deref(), Some(&root));
+        assert_eq!(error.input.as_deref(), Some("a;\ninclude none.conf;"));
+    }
+
+    #[test]
+    fn include_cycle() {
+        let dir = TempDir::new("cycle");
+        let root = dir.write("a.conf", "include b.conf;");
+        dir.write("b.conf", "x;\n  include a.conf;");
+        let error = parse_file(&root).unwrap_err();
+        assert_eq!(
+            error.kind,
+            ParseErrorKind::IncludeCycle(dir.0.join("a.conf"))
+        );
+        assert_eq!((error.line, error.column), (2, 3));
+        assert!(error.file.unwrap().ends_with("b.conf"));
+    }
+
+    #[test]
+    fn errors_in_included_files_name_the_file() {
+        let dir = TempDir::new("error");
+        let root = dir.write("a.conf", "include b.conf;");
+        dir.write("b.conf", "x 'y;");
+        let error = parse_file(&root).unwrap_err();
+        assert_eq!(error.kind, ParseErrorKind::UnterminatedQuote);
+        assert!(error.file.unwrap().ends_with("b.conf"));
+        // the text of the file the error is in, not of the one including it
+        assert_eq!(error.input.as_deref(), Some("x 'y;"));
+
+        let root = dir.write("a.conf", "include;");
+        let error = parse_file(&root).unwrap_err();
+        assert!(matches!(error.kind, ParseErrorKind::InvalidInclude(_)));
+        assert_eq!(error.input.as_deref(), Some("include;"));
+    }
+}
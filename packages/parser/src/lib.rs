@@ -1,147 +1,535 @@
-use std::mem::swap;
+pub mod borrowed;
+pub mod cst;
+mod include;
+pub mod schema;
+mod writer;
 
-#[derive(Debug, PartialEq)]
+// lets the code generated by `FromBlock` name this crate from inside it
+extern crate self as parser;
+
+pub use include::parse_file;
+
+use std::{
+    borrow::Cow,
+    error::Error,
+    fmt,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ParameterType {
     SingleQuote,
     DoubleQuote,
     Simple,
 }
+
+/// A location in the source. `line` and `column` are 1-based, `column`
+/// counts characters and `offset` counts bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Position {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+/// The source range a node was parsed from. `end` is exclusive. Nodes built
+/// in code rather than parsed have the default, all-zero span.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+    /// The file the source was read from, if it didn't come from a string.
+    pub file: Option<Arc<PathBuf>>,
+}
+
+impl Span {
+    pub fn range(&self) -> Range<usize> {
+        self.start.offset..self.end.offset
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(
+                f,
+                "{}:{}:{}",
+                file.display(),
+                self.start.line,
+                self.start.column
+            ),
+            None => write!(f, "line {}, column {}", self.start.line, self.start.column),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Parameter {
+    pub parameter_type: ParameterType,
+    pub value: String,
+    /// Covers the quotes of quoted parameters.
+    pub span: Span,
+}
+
 #[derive(Debug)]
 pub struct Directive {
     pub name: String,
-    pub parameters: Vec<(ParameterType, String)>,
+    pub parameters: Vec<Parameter>,
     pub block: Option<Block>,
+    /// From the first character of the name to the closing `;` or `}`.
+    pub span: Span,
+}
+
+/// A `#` comment running to the end of the line.
+#[derive(Debug, PartialEq)]
+pub struct Comment {
+    /// The text after `#`, without the line break.
+    pub text: String,
+    pub span: Span,
 }
 
 #[derive(Debug)]
 pub struct Block {
     pub directives: Vec<Directive>,
+    /// Comments directly inside this block, including those between the
+    /// parameters of its directives, in source order.
+    pub comments: Vec<Comment>,
+    /// From `{` to `}` for nested blocks, the whole input for the root block.
+    pub span: Span,
 }
 
-fn parse_block(input: &str) -> (Block, &str) {
-    let mut block = Block {
-        directives: Vec::new(),
-    };
-    let mut rest = input;
-    let mut last_index;
-    'outer: loop {
-        'inner: for (i, char) in rest.chars().enumerate() {
-            last_index = i;
-            match char {
-                '}' => break 'outer,
-                ' ' | '\t' | '\n' => continue,
-                _ => {}
-            };
-            let result = parse_directive(rest);
-            block.directives.push(result.0);
-            rest = result.1;
-            if rest.trim() == "" {
-                break 'outer;
+impl Parameter {
+    /// An unquoted parameter, it is quoted when written if the value needs it.
+    pub fn new(value: impl Into<String>) -> Self {
+        Self {
+            parameter_type: ParameterType::Simple,
+            value: value.into(),
+            span: Span::default(),
+        }
+    }
+}
+
+impl Directive {
+    pub fn new(name: impl Into<String>, parameters: Vec<Parameter>, block: Option<Block>) -> Self {
+        Self {
+            name: name.into(),
+            parameters,
+            block,
+            span: Span::default(),
+        }
+    }
+}
+
+impl Block {
+    pub fn new(directives: Vec<Directive>) -> Self {
+        Self {
+            directives,
+            comments: Vec::new(),
+            span: Span::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseErrorKind {
+    /// A directive name started with a character that can't start a name.
+    InvalidDirectiveName(char),
+    /// A character that isn't allowed at this position (e.g. `}` between parameters).
+    UnexpectedCharacter(char),
+    /// A `}` without a matching `{`.
+    UnexpectedClosingBrace,
+    /// A quoted parameter without its closing quote.
+    UnterminatedQuote,
+    /// A `{` without a matching `}`.
+    UnclosedBlock,
+    /// The input ended before the directive was terminated by `;` or a block.
+    UnexpectedEof,
+    /// A file couldn't be read or an include pattern couldn't be expanded.
+    Io(String),
+    /// An `include` without exactly one parameter, with a block or with an
+    /// invalid glob pattern.
+    InvalidInclude(String),
+    /// The file is already being included further up the include chain.
+    IncludeCycle(PathBuf),
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseErrorKind::InvalidDirectiveName(c) => {
+                write!(f, "invalid directive name: unexpected {:?}", c)
             }
-            break 'inner;
-        }
-    }
-    let rest = if rest.trim() == "" {
-        ""
-    } else {
-        &rest[last_index..]
-    };
-    (block, rest)
-}
-
-pub fn parse_directive(input: &str) -> (Directive, &str) {
-    let mut name = String::new();
-    let mut parameters = Vec::new();
-
-    let mut parameter_type = ParameterType::Simple;
-    let mut parameter_value = String::new();
-    let mut is_parameter_ended = true;
-    let mut is_name = true;
-    let mut last_index = 0;
-    let mut block = None;
-    let mut rest = None;
-    for (i, char) in input.chars().enumerate() {
-        last_index = i;
-        if is_name {
-            if name.is_empty() {
-                if char.is_numeric() {
-                    panic!("Parser error: expected character found number {}", char);
-                } else if char == '\n' || char == '\r' || char == ' ' {
-                    continue;
-                }
-            } else if char == ' ' {
-                is_name = false;
-                continue;
-            } else if char == ';' {
-                break;
+            ParseErrorKind::UnexpectedCharacter(c) => write!(f, "unexpected {:?}", c),
+            ParseErrorKind::UnexpectedClosingBrace => write!(f, "unexpected '}}'"),
+            ParseErrorKind::UnterminatedQuote => write!(f, "unterminated quoted parameter"),
+            ParseErrorKind::UnclosedBlock => write!(f, "block is never closed, expected '}}'"),
+            ParseErrorKind::UnexpectedEof => {
+                write!(f, "unexpected end of input, expected ';' or '{{'")
             }
-            name.push(char);
+            ParseErrorKind::Io(e) => write!(f, "{}", e),
+            ParseErrorKind::InvalidInclude(e) => write!(f, "invalid include: {}", e),
+            ParseErrorKind::IncludeCycle(path) => {
+                write!(f, "include cycle: {} includes itself", path.display())
+            }
+        }
+    }
+}
+
+/// A parse failure and the position it was detected at. `line` and `column`
+/// are 1-based, `column` counts characters and `offset` counts bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+    /// The file the error is in, if the input came from a file.
+    pub file: Option<Arc<PathBuf>>,
+    /// The text of `file` as it was parsed, set by [`parse_file`] unless the
+    /// file couldn't be read.
+    pub input: Option<Arc<str>>,
+}
+
+impl ParseError {
+    fn new(kind: ParseErrorKind, position: Position, file: Option<Arc<PathBuf>>) -> Self {
+        Self {
+            kind,
+            offset: position.offset,
+            line: position.line,
+            column: position.column,
+            file,
+            input: None,
+        }
+    }
+
+    fn at_span(kind: ParseErrorKind, span: &Span) -> Self {
+        Self::new(kind, span.start, span.file.clone())
+    }
+
+    /// Renders the offending line of `source` with a caret under the error
+    /// position, e.g.
+    ///
+    /// ```text
+    /// error: unterminated quoted parameter
+    ///  --> line 2, column 11
+    ///   |
+    /// 2 |     hello 'test;
+    ///   |           ^
+    /// ```
+    pub fn render_snippet(&self, source: &str) -> String {
+        let line = source.lines().nth(self.line - 1).unwrap_or("");
+        let location = match &self.file {
+            Some(file) => format!("{}:{}:{}", file.display(), self.line, self.column),
+            None => format!("line {}, column {}", self.line, self.column),
+        };
+        let line_number = self.line.to_string();
+        let gutter = " ".repeat(line_number.len());
+        let padding: String = line
+            .chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        format!(
+            "error: {}\n{}--> {}\n{} |\n{} | {}\n{} | {}^\n",
+            self.kind, gutter, location, gutter, line_number, line, gutter, padding
+        )
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(
+                f,
+                "{} at {}:{}:{}",
+                self.kind,
+                file.display(),
+                self.line,
+                self.column
+            ),
+            None => write!(
+                f,
+                "{} at line {}, column {}",
+                self.kind, self.line, self.column
+            ),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+struct Parser<'a> {
+    input: &'a str,
+    offset: usize,
+    line: usize,
+    column: usize,
+    file: Option<Arc<PathBuf>>,
+    comments: Vec<borrowed::Comment<'a>>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a str, file: Option<Arc<PathBuf>>) -> Self {
+        Self {
+            input,
+            offset: 0,
+            line: 1,
+            column: 1,
+            file,
+            comments: Vec::new(),
+        }
+    }
+
+    fn position(&self) -> Position {
+        Position {
+            offset: self.offset,
+            line: self.line,
+            column: self.column,
+        }
+    }
+
+    fn span_from(&self, start: Position) -> Span {
+        Span {
+            start,
+            end: self.position(),
+            file: self.file.clone(),
+        }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.input[self.offset..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let char = self.peek()?;
+        self.offset += char.len_utf8();
+        if char == '\n' {
+            self.line += 1;
+            self.column = 1;
         } else {
-            if char == ';' {
-                if let ParameterType::Simple = parameter_type {
-                    if parameter_value.is_empty() {
-                        break;
-                    }
-                }
-                let param = (parameter_type, parameter_value);
-                parameters.push(param);
+            self.column += 1;
+        }
+        Some(char)
+    }
+
+    fn error(&self, kind: ParseErrorKind) -> ParseError {
+        ParseError::new(kind, self.position(), self.file.clone())
+    }
+
+    /// Skips whitespace and comments, collecting the comments.
+    fn skip_whitespace(&mut self) {
+        while let Some(char) = self.peek() {
+            if char == '#' {
+                let comment = self.parse_comment();
+                self.comments.push(comment);
+            } else if char.is_whitespace() {
+                self.bump();
+            } else {
                 break;
             }
-            if is_parameter_ended {
-                match char {
-                    ' ' => continue,
-                    '{' => {
-                        let result = parse_block(&input[last_index + 1..]);
-                        block = Some(result.0);
-                        rest = Some(result.1);
-                        break;
-                    }
-                    _ => {}
+        }
+    }
+
+    fn parse_comment(&mut self) -> borrowed::Comment<'a> {
+        let start = self.position();
+        self.bump();
+        let text_start = self.offset;
+        while !matches!(self.peek(), None | Some('\n')) {
+            self.bump();
+        }
+        let text = self.input[text_start..self.offset].trim_end_matches('\r');
+        borrowed::Comment {
+            text,
+            span: self.span_from(start),
+        }
+    }
+
+    /// Parses directives until the end of input, or until the matching `}`
+    /// when `nested` is set. For nested blocks `start` is the position of the
+    /// already consumed `{` and the closing brace is consumed too.
+    fn parse_block(
+        &mut self,
+        start: Position,
+        nested: bool,
+    ) -> Result<borrowed::Block<'a>, ParseError> {
+        let opening = ParseError::new(ParseErrorKind::UnclosedBlock, start, self.file.clone());
+        let mut directives = Vec::new();
+        // comments of nested blocks are taken by the time this one ends
+        let first_comment = self.comments.len();
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                None if nested => return Err(opening),
+                None => break,
+                Some('}') if nested => {
+                    self.bump();
+                    break;
                 }
-            }
-            if parameter_value.is_empty() && is_parameter_ended {
-                parameter_type = match char {
-                    '\'' => ParameterType::SingleQuote,
-                    '"' => ParameterType::DoubleQuote,
-                    _ => {
-                        parameter_value.push(char);
-                        ParameterType::Simple
-                    }
-                };
-                is_parameter_ended = false;
-            } else {
-                match (&parameter_type, char) {
-                    (ParameterType::SingleQuote, '\'')
-                    | (ParameterType::DoubleQuote, '"')
-                    | (ParameterType::Simple, ' ' | '\t' | ';') => {
-                        is_parameter_ended = true;
-                        let mut value = String::new();
-                        let mut par_type = ParameterType::Simple;
-                        swap(&mut value, &mut parameter_value);
-                        swap(&mut parameter_type, &mut par_type);
-                        let param = (par_type, value);
-                        parameters.push(param);
-                        continue;
-                    }
-                    _ => {}
-                };
-                parameter_value.push(char);
+                Some('}') => return Err(self.error(ParseErrorKind::UnexpectedClosingBrace)),
+                Some(_) => directives.push(self.parse_directive()?),
             }
         }
+        Ok(borrowed::Block {
+            directives,
+            comments: self.comments.split_off(first_comment),
+            span: self.span_from(start),
+        })
     }
-    (
-        Directive {
-            block,
+
+    fn parse_directive(&mut self) -> Result<borrowed::Directive<'a>, ParseError> {
+        self.skip_whitespace();
+        let start = self.position();
+        let name = match self.peek() {
+            None => return Err(self.error(ParseErrorKind::UnexpectedEof)),
+            Some(char) if char.is_numeric() || is_special(char) => {
+                return Err(self.error(ParseErrorKind::InvalidDirectiveName(char)))
+            }
+            Some(_) => self.parse_simple(),
+        };
+        let mut parameters = Vec::new();
+        let mut block = None;
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                None => return Err(self.error(ParseErrorKind::UnexpectedEof)),
+                Some(';') => {
+                    self.bump();
+                    break;
+                }
+                Some('{') => {
+                    let block_start = self.position();
+                    self.bump();
+                    block = Some(self.parse_block(block_start, true)?);
+                    break;
+                }
+                Some('}') => return Err(self.error(ParseErrorKind::UnexpectedCharacter('}'))),
+                Some(_) => parameters.push(self.parse_parameter()?),
+            }
+        }
+        Ok(borrowed::Directive {
             name,
             parameters,
-        },
-        rest.map(|x| &x[1..])
-            .unwrap_or_else(|| &input[last_index + 1..]),
-    )
+            block,
+            span: self.span_from(start),
+        })
+    }
+
+    fn parse_parameter(&mut self) -> Result<borrowed::Parameter<'a>, ParseError> {
+        let start = self.position();
+        let (parameter_type, quote) = match self.peek() {
+            Some('\'') => (ParameterType::SingleQuote, '\''),
+            Some('"') => (ParameterType::DoubleQuote, '"'),
+            _ => {
+                let value = unescape(self.parse_simple());
+                return Ok(borrowed::Parameter {
+                    parameter_type: ParameterType::Simple,
+                    value,
+                    span: self.span_from(start),
+                });
+            }
+        };
+        let opening = self.error(ParseErrorKind::UnterminatedQuote);
+        self.bump();
+        let value_start = self.offset;
+        loop {
+            match self.bump() {
+                None => return Err(opening),
+                Some('\\') => {
+                    self.bump();
+                }
+                Some(char) if char == quote => break,
+                Some(_) => {}
+            }
+        }
+        let value = unescape(&self.input[value_start..self.offset - quote.len_utf8()]);
+        Ok(borrowed::Parameter {
+            parameter_type,
+            value,
+            span: self.span_from(start),
+        })
+    }
+
+    /// Reads an unquoted word up to the next whitespace or special character.
+    /// A backslash keeps the character after it in the word, and so does a
+    /// `${...}` variable for its braces.
+    fn parse_simple(&mut self) -> &'a str {
+        let start = self.offset;
+        let mut in_variable = false;
+        while let Some(char) = self.peek() {
+            match char {
+                '}' if in_variable => in_variable = false,
+                '{' if self.input[..self.offset].ends_with('$') => in_variable = true,
+                char if char.is_whitespace() || is_special(char) => break,
+                _ => {}
+            }
+            self.bump();
+            if char == '\\' {
+                self.bump();
+            }
+        }
+        &self.input[start..self.offset]
+    }
+}
+
+/// Resolves the escapes nginx understands (`\"`, `\'`, `\\`, `\n`, `\r` and
+/// `\t`) and keeps any other backslash as is. Line breaks written as CRLF
+/// become a plain `\n`, so a value doesn't change when a file is saved with
+/// Windows line endings. Borrows `raw` when there is nothing to replace.
+fn unescape(raw: &str) -> Cow<'_, str> {
+    if !raw.contains(&['\\', '\r'][..]) {
+        return Cow::Borrowed(raw);
+    }
+    let mut value = String::with_capacity(raw.len());
+    let mut chars = raw.chars().peekable();
+    while let Some(char) = chars.next() {
+        match (char, chars.peek()) {
+            ('\\', Some(&escaped @ ('"' | '\'' | '\\'))) => {
+                chars.next();
+                value.push(escaped);
+            }
+            ('\\', Some(&escaped @ ('n' | 'r' | 't'))) => {
+                chars.next();
+                value.push(match escaped {
+                    'n' => '\n',
+                    'r' => '\r',
+                    _ => '\t',
+                });
+            }
+            ('\r', Some('\n')) => {}
+            (char, _) => value.push(char),
+        }
+    }
+    Cow::Owned(value)
+}
+
+fn is_special(char: char) -> bool {
+    matches!(char, ';' | '{' | '}' | '\'' | '"')
+}
+
+/// Parses a single directive from the start of `input` and returns it along
+/// with the unparsed rest of the input.
+pub fn parse_directive(input: &str) -> Result<(Directive, &str), ParseError> {
+    let mut parser = Parser::new(input, None);
+    let directive = parser.parse_directive()?;
+    Ok((directive.into_owned(), parser.rest()))
 }
 
-pub fn parse(input: &str) -> Block {
-    parse_block(input).0
+pub fn parse(input: &str) -> Result<Block, ParseError> {
+    borrowed::parse(input).map(borrowed::Block::into_owned)
+}
+
+/// Like [`parse`], but records `file` as the origin of every span.
+pub fn parse_with_file(input: &str, file: &Path) -> Result<Block, ParseError> {
+    let mut parser = Parser::new(input, Some(Arc::new(file.to_path_buf())));
+    let start = parser.position();
+    parser
+        .parse_block(start, false)
+        .map(borrowed::Block::into_owned)
 }
 
 #[cfg(test)]
@@ -158,46 +546,53 @@ directive1 'par1' par3 "'a" {
 }
 dir2;
         "#,
-        );
+        )
+        .unwrap();
         assert_eq!(result.directives.len(), 2);
-        let directive1 = result.directives.get(0).unwrap();
+        let directive1 = result.directives.first().unwrap();
         assert_eq!(directive1.parameters.len(), 3);
         assert_eq!(directive1.name, "directive1");
         let params = &directive1.parameters;
 
-        assert_eq!(params.get(0).unwrap().0, ParameterType::SingleQuote);
-        assert_eq!(params.get(1).unwrap().0, ParameterType::Simple);
-        assert_eq!(params.get(2).unwrap().0, ParameterType::DoubleQuote);
-        assert_eq!(params.get(0).unwrap().1, "par1");
-        assert_eq!(params.get(1).unwrap().1, "par3");
-        assert_eq!(params.get(2).unwrap().1, "'a");
+        assert_eq!(
+            params.first().unwrap().parameter_type,
+            ParameterType::SingleQuote
+        );
+        assert_eq!(params.get(1).unwrap().parameter_type, ParameterType::Simple);
+        assert_eq!(
+            params.get(2).unwrap().parameter_type,
+            ParameterType::DoubleQuote
+        );
+        assert_eq!(params.first().unwrap().value, "par1");
+        assert_eq!(params.get(1).unwrap().value, "par3");
+        assert_eq!(params.get(2).unwrap().value, "'a");
 
         let block = directive1.block.as_ref().unwrap();
         assert_eq!(block.directives.len(), 2);
-        assert_eq!(block.directives.get(0).unwrap().name, "hello");
+        assert_eq!(block.directives.first().unwrap().name, "hello");
         assert_eq!(block.directives.get(1).unwrap().name, "hi");
-        assert_eq!(block.directives.get(0).unwrap().parameters.len(), 1);
+        assert_eq!(block.directives.first().unwrap().parameters.len(), 1);
         assert_eq!(block.directives.get(1).unwrap().parameters.len(), 1);
         assert_eq!(
             block
                 .directives
-                .get(0)
+                .first()
                 .unwrap()
                 .parameters
-                .get(0)
+                .first()
                 .unwrap()
-                .0,
+                .parameter_type,
             ParameterType::SingleQuote
         );
         assert_eq!(
             block
                 .directives
-                .get(0)
+                .first()
                 .unwrap()
                 .parameters
-                .get(0)
+                .first()
                 .unwrap()
-                .1,
+                .value,
             "test"
         );
 
@@ -206,4 +601,194 @@ dir2;
         assert_eq!(directive2.parameters.len(), 0);
         assert!(directive2.block.is_none());
     }
+
+    fn parse_err(input: &str) -> ParseError {
+        parse(input).unwrap_err()
+    }
+
+    #[test]
+    fn parse_directive_returns_rest() {
+        let (directive, rest) = parse_directive("  listen 80; next;").unwrap();
+        assert_eq!(directive.name, "listen");
+        assert_eq!(directive.parameters.len(), 1);
+        assert_eq!(directive.parameters[0].value, "80");
+        assert_eq!(rest, " next;");
+    }
+
+    #[test]
+    fn empty_input() {
+        assert!(parse("").unwrap().directives.is_empty());
+        assert!(parse(" \n\t ").unwrap().directives.is_empty());
+    }
+
+    #[test]
+    fn name_starting_with_number() {
+        let error = parse_err("http {\n    1listen 80;\n}");
+        assert_eq!(error.kind, ParseErrorKind::InvalidDirectiveName('1'));
+        assert_eq!((error.offset, error.line, error.column), (11, 2, 5));
+    }
+
+    #[test]
+    fn unterminated_quote() {
+        let error = parse_err("server_name 'abc;\nlisten 80;");
+        assert_eq!(error.kind, ParseErrorKind::UnterminatedQuote);
+        assert_eq!((error.line, error.column), (1, 13));
+    }
+
+    #[test]
+    fn missing_closing_brace() {
+        let error = parse_err("http {\n    server {\n    }\n");
+        assert_eq!(error.kind, ParseErrorKind::UnclosedBlock);
+        assert_eq!((error.line, error.column), (1, 6));
+    }
+
+    #[test]
+    fn unexpected_closing_brace() {
+        let error = parse_err("a;\n}");
+        assert_eq!(error.kind, ParseErrorKind::UnexpectedClosingBrace);
+        assert_eq!((error.line, error.column), (2, 1));
+        let error = parse_err("http { listen 80 }");
+        assert_eq!(error.kind, ParseErrorKind::UnexpectedCharacter('}'));
+    }
+
+    #[test]
+    fn missing_semicolon_at_eof() {
+        let error = parse_err("listen 80");
+        assert_eq!(error.kind, ParseErrorKind::UnexpectedEof);
+        assert_eq!((error.offset, error.line, error.column), (9, 1, 10));
+    }
+
+    #[test]
+    fn render_snippet() {
+        let source = "http {\n    hello 'test;\n}";
+        let error = parse_err(source);
+        assert_eq!(
+            error.render_snippet(source),
+            "error: unterminated quoted parameter\n --> line 2, column 11\n  |\n2 |     hello 'test;\n  |           ^\n"
+        );
+    }
+
+    #[test]
+    fn spans() {
+        let source = "http {\n    listen 'a b' 80;\n}\n";
+        let result = parse_with_file(source, Path::new("/etc/paykan/a.conf")).unwrap();
+        assert_eq!(result.span.range(), 0..source.len());
+        let http = &result.directives[0];
+        assert_eq!(http.span.range(), 0..source.len() - 1);
+        let block = http.block.as_ref().unwrap();
+        assert_eq!(&source[block.span.range()], "{\n    listen 'a b' 80;\n}");
+        let listen = &block.directives[0];
+        assert_eq!(&source[listen.span.range()], "listen 'a b' 80;");
+        assert_eq!((listen.span.start.line, listen.span.start.column), (2, 5));
+        assert_eq!((listen.span.end.line, listen.span.end.column), (2, 21));
+        assert_eq!(&source[listen.parameters[0].span.range()], "'a b'");
+        assert_eq!(&source[listen.parameters[1].span.range()], "80");
+        assert_eq!(listen.span.to_string(), "/etc/paykan/a.conf:2:5");
+        assert_eq!(
+            parse(source).unwrap().directives[0].span.to_string(),
+            "line 1, column 1"
+        );
+    }
+
+    #[test]
+    fn comments() {
+        let source = r#"
+# top level
+http { # after brace
+    listen 80 # between parameters
+        81; # trailing
+    server_name "a # not a comment" 'b#' c#d;
+    #}
+}
+# eof"#;
+        let result = parse(source).unwrap();
+        let texts = |block: &Block| -> Vec<String> {
+            block.comments.iter().map(|c| c.text.clone()).collect()
+        };
+        assert_eq!(texts(&result), vec![" top level", " eof"]);
+        assert_eq!(result.directives.len(), 1);
+        let http = result.directives[0].block.as_ref().unwrap();
+        assert_eq!(
+            texts(http),
+            vec![" after brace", " between parameters", " trailing", "}"]
+        );
+        let values: Vec<_> = http.directives[0]
+            .parameters
+            .iter()
+            .map(|p| p.value.as_str())
+            .collect();
+        assert_eq!(values, vec!["80", "81"]);
+        let values: Vec<_> = http.directives[1]
+            .parameters
+            .iter()
+            .map(|p| p.value.as_str())
+            .collect();
+        assert_eq!(values, vec!["a # not a comment", "b#", "c#d"]);
+        assert_eq!(&source[http.comments[0].span.range()], "# after brace");
+    }
+
+    fn values(directive: &Directive) -> Vec<&str> {
+        directive
+            .parameters
+            .iter()
+            .map(|p| p.value.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn escapes() {
+        let result =
+            parse(r#"a "say \"hi\"" 'it\'s' "tab\there" 'back\\slash' "new\nline" "\d" x\;y\ z;"#)
+                .unwrap();
+        assert_eq!(
+            values(&result.directives[0]),
+            vec![
+                "say \"hi\"",
+                "it's",
+                "tab\there",
+                "back\\slash",
+                "new\nline",
+                "\\d",
+                r"x\;y\ z"
+            ]
+        );
+        let error = parse_err(r#"a "open\";"#);
+        assert_eq!(error.kind, ParseErrorKind::UnterminatedQuote);
+    }
+
+    #[test]
+    fn multi_line_strings() {
+        let source = "a 'one\n  two' b;\nc;";
+        let result = parse(source).unwrap();
+        assert_eq!(values(&result.directives[0]), vec!["one\n  two", "b"]);
+        assert_eq!(result.directives[1].span.start.line, 3);
+    }
+
+    #[test]
+    fn windows_line_endings() {
+        let source = "# edited on windows\r\nhttp {\r\n    server {\r\n        \
+                      listen 80;\r\n        return 'multi\r\nline';\r\n    }\r\n}\r\n";
+        let result = parse(source).unwrap();
+        assert_eq!(result.comments[0].text, " edited on windows");
+        let http = result.directives[0].block.as_ref().unwrap();
+        let server = http.directives[0].block.as_ref().unwrap();
+        assert_eq!(values(&server.directives[0]), vec!["80"]);
+        assert_eq!(values(&server.directives[1]), vec!["multi\nline"]);
+        assert_eq!(server.directives[1].span.start.line, 5);
+        assert_eq!(&source[server.directives[0].span.range()], "listen 80;");
+
+        let error = parse_err("http {\r\n    listen 80\r\n}\r\n");
+        assert_eq!(error.kind, ParseErrorKind::UnexpectedCharacter('}'));
+        assert_eq!((error.line, error.column), (3, 1));
+    }
+
+    #[test]
+    fn braced_variables_in_unquoted_parameters() {
+        let result = parse("root /srv/${host}/a ${env:X:-8080} $x{}").unwrap();
+        assert_eq!(
+            values(&result.directives[0]),
+            vec!["/srv/${host}/a", "${env:X:-8080}", "$x"]
+        );
+        assert!(result.directives[0].block.is_some());
+    }
 }
@@ -0,0 +1,464 @@
+//! Building typed structs from a [`Block`], usually through
+//! `#[derive(FromBlock)]`. Problems are collected rather than returned on the
+//! first one, so a config with several mistakes reports all of them.
+//!
+//! ```ignore
+//! #[derive(FromBlock)]
+//! struct Server {
+//!     // required, exactly one parameter, parsed with `FromStr`
+//!     listen: SocketAddr,
+//!     // one or more parameters
+//!     #[directive(args = "1..")]
+//!     server_name: Vec<String>,
+//!     // optional
+//!     root: Option<String>,
+//!     #[directive(default = "75")]
+//!     keepalive_timeout: u64,
+//!     // every `location` directive, each with its own block. `repeated`
+//!     // fields are empty when the directive isn't used unless they have a
+//!     // `default`
+//!     #[directive(name = "location", repeated, block)]
+//!     locations: Vec<Location>,
+//!     // a function taking the `&Directive`
+//!     #[directive(parse_with = "parse_return")]
+//!     r#return: Option<Return>,
+//!     // `Limit` implements `FromDirective`
+//!     #[directive(from_directive)]
+//!     limit_except: Option<Limit>,
+//!     // not read from the config, set to `Default::default()`
+//!     #[directive(skip)]
+//!     requests: usize,
+//! }
+//! ```
+//!
+//! `#[from_block(name = "...")]` sets the name used in messages, the
+//! lowercase struct name by default, and `#[from_block(validate = "path")]`
+//! runs `fn(&Self, &mut Vec<Problem>)` once every field was read:
+//!
+//! ```ignore
+//! #[derive(FromBlock)]
+//! #[from_block(name = "server", validate = "Site::check")]
+//! struct Site { ... }
+//! ```
+use crate::{Block, Directive, Span};
+use std::{fmt, str::FromStr};
+
+/// Derives [`FromBlock`](trait@FromBlock), importing this brings both.
+pub use parser_derive::FromBlock;
+
+/// One thing wrong with the config and where it is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    pub message: String,
+    pub span: Span,
+}
+
+impl Problem {
+    pub fn new(message: impl Into<String>, span: &Span) -> Self {
+        Self {
+            message: message.into(),
+            span: span.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.span, self.message)
+    }
+}
+
+pub trait FromBlock: Sized {
+    /// The name used in messages about this block, e.g. `server`.
+    const NAME: &'static str;
+
+    /// Returns `None` if any problem was pushed to `problems`.
+    fn from_block(block: &Block, problems: &mut Vec<Problem>) -> Option<Self>;
+}
+
+/// Builds a value from a whole directive, for directives that take
+/// parameters and a block like `location /images { ... }`.
+pub trait FromDirective: Sized {
+    /// Returns `None` if any problem was pushed to `problems`.
+    fn from_directive(directive: &Directive, problems: &mut Vec<Problem>) -> Option<Self>;
+}
+
+/// Converts a single parameter value. Implemented for every `FromStr` type.
+pub trait FromParameter: Sized {
+    fn from_parameter(value: &str) -> Result<Self, String>;
+}
+
+impl<T> FromParameter for T
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    fn from_parameter(value: &str) -> Result<Self, String> {
+        T::from_str(value).map_err(|e| e.to_string())
+    }
+}
+
+/// Reports directives of `block` that aren't in `allowed`.
+pub fn unknown_directives(block: &Block, allowed: &[&str], problems: &mut Vec<Problem>) {
+    for directive in &block.directives {
+        if !allowed.contains(&directive.name.as_str()) {
+            problems.push(Problem::new(
+                format!("unknown directive \"{}\"", directive.name),
+                &directive.span,
+            ));
+        }
+    }
+}
+
+pub fn find_all<'a>(block: &'a Block, name: &'a str) -> impl Iterator<Item = &'a Directive> {
+    block.directives.iter().filter(move |d| d.name == name)
+}
+
+/// The first occurrence of a directive that may appear once. Later
+/// occurrences are reported as duplicates.
+pub fn find_one<'a>(
+    block: &'a Block,
+    name: &str,
+    problems: &mut Vec<Problem>,
+) -> Option<&'a Directive> {
+    let mut found = block.directives.iter().filter(|d| d.name == name);
+    let first = found.next();
+    for duplicate in found {
+        problems.push(Problem::new(
+            format!("\"{}\" directive is duplicate", name),
+            &duplicate.span,
+        ));
+    }
+    first
+}
+
+pub fn missing(block: &Block, parent: &str, name: &str, problems: &mut Vec<Problem>) {
+    problems.push(Problem::new(
+        format!("\"{}\" is missing a \"{}\" directive", parent, name),
+        &block.span,
+    ));
+}
+
+fn no_block(directive: &Directive, problems: &mut Vec<Problem>) -> bool {
+    if directive.block.is_some() {
+        problems.push(Problem::new(
+            format!("\"{}\" directive doesn't take a block", directive.name),
+            &directive.span,
+        ));
+    }
+    directive.block.is_none()
+}
+
+fn convert<T: FromParameter>(
+    directive: &Directive,
+    index: usize,
+    problems: &mut Vec<Problem>,
+) -> Option<T> {
+    let parameter = &directive.parameters[index];
+    T::from_parameter(&parameter.value)
+        .map_err(|e| {
+            problems.push(Problem::new(
+                format!(
+                    "invalid value \"{}\" in \"{}\" directive: {}",
+                    parameter.value, directive.name, e
+                ),
+                &parameter.span,
+            ))
+        })
+        .ok()
+}
+
+/// The value of a directive that takes exactly one parameter and no block.
+pub fn parameter<T: FromParameter>(
+    directive: &Directive,
+    problems: &mut Vec<Problem>,
+) -> Option<T> {
+    let valid = no_block(directive, problems);
+    if directive.parameters.len() != 1 {
+        problems.push(Problem::new(
+            format!(
+                "\"{}\" directive takes exactly one argument",
+                directive.name
+            ),
+            &directive.span,
+        ));
+        return None;
+    }
+    convert(directive, 0, problems).filter(|_| valid)
+}
+
+/// The values of a directive taking `min..=max` parameters and no block.
+pub fn parameters<T: FromParameter>(
+    directive: &Directive,
+    min: usize,
+    max: Option<usize>,
+    problems: &mut Vec<Problem>,
+) -> Option<Vec<T>> {
+    let mut valid = no_block(directive, problems);
+    let count = directive.parameters.len();
+    if count < min || max.is_some_and(|max| count > max) {
+        let expected = match max {
+            Some(max) if max == min => format!("exactly {}", min),
+            Some(max) => format!("{} to {}", min, max),
+            None => format!("at least {}", min),
+        };
+        problems.push(Problem::new(
+            format!(
+                "\"{}\" directive takes {} argument{}",
+                directive.name,
+                expected,
+                if max == Some(1) || (max.is_none() && min == 1) {
+                    ""
+                } else {
+                    "s"
+                }
+            ),
+            &directive.span,
+        ));
+        return None;
+    }
+    let mut values = Vec::with_capacity(count);
+    for index in 0..count {
+        match convert(directive, index, problems) {
+            Some(value) => values.push(value),
+            None => valid = false,
+        }
+    }
+    Some(values).filter(|_| valid)
+}
+
+/// Converts a directive with a `parse_with` function. Its error is reported
+/// at the directive.
+pub fn parse_with<T>(
+    directive: &Directive,
+    problems: &mut Vec<Problem>,
+    parse: fn(&Directive) -> Result<T, String>,
+) -> Option<T> {
+    parse(directive)
+        .map_err(|message| problems.push(Problem::new(message, &directive.span)))
+        .ok()
+}
+
+pub fn from_directive<T: FromDirective>(
+    directive: &Directive,
+    problems: &mut Vec<Problem>,
+) -> Option<T> {
+    T::from_directive(directive, problems)
+}
+
+/// The contents of a directive that takes a block and no parameters.
+pub fn block<T: FromBlock>(directive: &Directive, problems: &mut Vec<Problem>) -> Option<T> {
+    if !directive.parameters.is_empty() {
+        problems.push(Problem::new(
+            format!("\"{}\" directive takes no arguments", directive.name),
+            &directive.span,
+        ));
+    }
+    match &directive.block {
+        Some(block) => T::from_block(block, problems),
+        None => {
+            problems.push(Problem::new(
+                format!("\"{}\" directive requires a block", directive.name),
+                &directive.span,
+            ));
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+    use std::net::SocketAddr;
+
+    #[derive(Debug, FromBlock)]
+    struct Location {
+        root: String,
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Return(u16, Option<String>);
+
+    fn parse_return(directive: &Directive) -> Result<Return, String> {
+        match &directive.parameters[..] {
+            [code] | [code, _] => {
+                let text = directive.parameters.get(1).map(|p| p.value.clone());
+                code.value
+                    .parse()
+                    .map(|code| Return(code, text))
+                    .map_err(|_| format!("invalid return code \"{}\"", code.value))
+            }
+            _ => Err("invalid number of arguments in \"return\" directive".to_string()),
+        }
+    }
+
+    #[derive(Debug, FromBlock)]
+    #[from_block(name = "server")]
+    struct Site {
+        listen: SocketAddr,
+        #[directive(args = "1..")]
+        server_name: Vec<String>,
+        index: Option<String>,
+        #[directive(default = "75")]
+        keepalive_timeout: u64,
+        #[directive(args = 2)]
+        error_page: Option<Vec<String>>,
+        #[directive(name = "location", repeated, block)]
+        locations: Vec<Location>,
+        #[directive(repeated)]
+        allow: Vec<String>,
+        #[directive(repeated, default = "vec![\"html\".to_string()]")]
+        types: Vec<String>,
+        #[directive(parse_with = "parse_return")]
+        r#return: Option<Return>,
+        #[directive(skip)]
+        requests: usize,
+    }
+
+    fn from_str<T: FromBlock>(source: &str) -> Result<T, Vec<(usize, String)>> {
+        let mut problems = Vec::new();
+        let block = parse(source).unwrap();
+        T::from_block(&block, &mut problems).ok_or_else(|| {
+            problems
+                .into_iter()
+                .map(|p| (p.span.start.line, p.message))
+                .collect()
+        })
+    }
+
+    #[test]
+    fn builds_struct() {
+        let site: Site = from_str(
+            "listen 127.0.0.1:80; server_name a b;\n\
+             location { root /a; } location { root /b; }\n\
+             allow 10.0.0.1; allow all; return 301 /x;",
+        )
+        .unwrap();
+        assert_eq!(site.listen, "127.0.0.1:80".parse().unwrap());
+        assert_eq!(site.server_name, ["a", "b"]);
+        assert_eq!(site.index, None);
+        assert_eq!(site.keepalive_timeout, 75);
+        assert_eq!(site.error_page, None);
+        let roots: Vec<_> = site.locations.iter().map(|l| &l.root[..]).collect();
+        assert_eq!(roots, ["/a", "/b"]);
+        assert_eq!(site.allow, ["10.0.0.1", "all"]);
+        assert_eq!(site.types, ["html"]);
+        assert_eq!(site.r#return, Some(Return(301, Some("/x".to_string()))));
+        assert_eq!(site.requests, 0);
+
+        let site: Site = from_str(
+            "listen [::1]:8080; server_name a; keepalive_timeout 5; error_page 404 /e;\n\
+                      types css; types js;",
+        )
+        .unwrap();
+        assert_eq!(site.keepalive_timeout, 5);
+        assert!(site.allow.is_empty());
+        assert_eq!(site.error_page.unwrap(), ["404", "/e"]);
+        assert_eq!(site.types, ["css", "js"]);
+    }
+
+    #[test]
+    fn reports_problems() {
+        let problems = from_str::<Site>(
+            "server_name;\n\
+             keepalive_timeout soon;\n\
+             error_page 404;\n\
+             location /a { root /a; }\n\
+             location { }\n\
+             index a; index b;\n\
+             return abc;\n\
+             gzip on;\n\
+             allow { }",
+        )
+        .unwrap_err();
+        let expected = [
+            (1, "\"server_name\" directive takes at least 1 argument"),
+            (2, "invalid value \"soon\" in \"keepalive_timeout\" directive: invalid digit found in string"),
+            (3, "\"error_page\" directive takes exactly 2 arguments"),
+            (4, "\"location\" directive takes no arguments"),
+            (5, "\"location\" is missing a \"root\" directive"),
+            (6, "\"index\" directive is duplicate"),
+            (7, "invalid return code \"abc\""),
+            (8, "unknown directive \"gzip\""),
+            (9, "\"allow\" directive doesn't take a block"),
+            (9, "\"allow\" directive takes exactly one argument"),
+            (1, "\"server\" is missing a \"listen\" directive"),
+        ];
+        let mut problems = problems;
+        problems.sort();
+        let mut expected: Vec<_> = expected.iter().map(|(l, m)| (*l, m.to_string())).collect();
+        expected.sort();
+        assert_eq!(problems, expected);
+    }
+
+    #[derive(Debug, FromBlock)]
+    struct Weights {
+        weight: u32,
+    }
+
+    #[derive(Debug)]
+    struct Backend {
+        address: String,
+        weight: u32,
+    }
+
+    impl FromDirective for Backend {
+        fn from_directive(directive: &Directive, problems: &mut Vec<Problem>) -> Option<Self> {
+            let address = directive.parameters.first().map(|p| p.value.clone());
+            let weights: Option<Weights> = directive
+                .block
+                .as_ref()
+                .and_then(|block| Weights::from_block(block, problems));
+            if address.is_none() {
+                problems.push(Problem::new("backend needs an address", &directive.span));
+            }
+            Some(Backend {
+                address: address?,
+                weight: weights?.weight,
+            })
+        }
+    }
+
+    #[derive(Debug, FromBlock)]
+    #[from_block(validate = "Upstream::validate")]
+    struct Upstream {
+        #[directive(name = "backend", repeated, from_directive)]
+        backends: Vec<Backend>,
+    }
+
+    impl Upstream {
+        fn validate(&self, problems: &mut Vec<Problem>) {
+            if self.backends.iter().all(|b| b.weight == 0) {
+                problems.push(Problem::new("every weight is 0", &Span::default()));
+            }
+        }
+    }
+
+    #[test]
+    fn builds_from_directives() {
+        let upstream: Upstream =
+            from_str("backend a { weight 2; }\nbackend b { weight 0; }").unwrap();
+        let backends: Vec<_> = upstream
+            .backends
+            .iter()
+            .map(|b| (&b.address[..], b.weight))
+            .collect();
+        assert_eq!(backends, [("a", 2), ("b", 0)]);
+
+        assert_eq!(
+            from_str::<Upstream>("backend { weight 1; }\nbackend b { }").unwrap_err(),
+            [
+                (1, "backend needs an address".to_string()),
+                (
+                    2,
+                    "\"weights\" is missing a \"weight\" directive".to_string()
+                ),
+            ]
+        );
+        assert_eq!(
+            from_str::<Upstream>("backend a { weight 0; }").unwrap_err(),
+            [(0, "every weight is 0".to_string())]
+        );
+    }
+}
@@ -0,0 +1,173 @@
+//! Writes the AST back as config text. Comments aren't part of the output,
+//! use [`cst`](crate::cst) to edit a config without losing them.
+use crate::{is_special, Block, Directive, Parameter, ParameterType};
+use std::fmt;
+
+const INDENT: &str = "    ";
+
+/// Writes `value` as a parameter of the given type that parses back to
+/// `value`. Simple values that can't be written unquoted get double quotes.
+pub(crate) fn quote(value: &str, parameter_type: ParameterType) -> (ParameterType, String) {
+    let (parameter_type, quote) = match parameter_type {
+        ParameterType::Simple if can_be_unquoted(value) => {
+            return (ParameterType::Simple, value.to_string())
+        }
+        ParameterType::SingleQuote => (ParameterType::SingleQuote, '\''),
+        _ => (ParameterType::DoubleQuote, '"'),
+    };
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push(quote);
+    for char in value.chars() {
+        match char {
+            '\\' => quoted.push_str("\\\\"),
+            // would be read back as part of a CRLF line break
+            '\r' => quoted.push_str("\\r"),
+            char if char == quote => {
+                quoted.push('\\');
+                quoted.push(char);
+            }
+            char => quoted.push(char),
+        }
+    }
+    quoted.push(quote);
+    (parameter_type, quoted)
+}
+
+fn can_be_unquoted(value: &str) -> bool {
+    let mut chars = value.chars().peekable();
+    if value.is_empty() || value.starts_with('#') {
+        return false;
+    }
+    while let Some(char) = chars.next() {
+        match char {
+            '\\' => {
+                if let None | Some('"' | '\'' | '\\' | 'n' | 'r' | 't') = chars.peek() {
+                    return false;
+                }
+            }
+            char if char.is_whitespace() || is_special(char) => return false,
+            _ => {}
+        }
+    }
+    true
+}
+
+fn write_directive(f: &mut fmt::Formatter<'_>, directive: &Directive, depth: usize) -> fmt::Result {
+    write!(f, "{}{}", INDENT.repeat(depth), directive.name)?;
+    for parameter in &directive.parameters {
+        write!(f, " {}", parameter)?;
+    }
+    match &directive.block {
+        None => f.write_str(";"),
+        Some(block) => {
+            f.write_str(" {\n")?;
+            for inner in &block.directives {
+                write_directive(f, inner, depth + 1)?;
+                f.write_str("\n")?;
+            }
+            write!(f, "{}}}", INDENT.repeat(depth))
+        }
+    }
+}
+
+impl fmt::Display for Parameter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&quote(&self.value, self.parameter_type).1)
+    }
+}
+
+impl fmt::Display for Directive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_directive(f, self, 0)
+    }
+}
+
+/// Writes one directive per line with four spaces of indentation per level.
+impl fmt::Display for Block {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for directive in &self.directives {
+            write_directive(f, directive, 0)?;
+            f.write_str("\n")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    fn parameter(parameter_type: ParameterType, value: &str) -> Parameter {
+        Parameter {
+            parameter_type,
+            ..Parameter::new(value)
+        }
+    }
+
+    #[test]
+    fn writes_nested_blocks() {
+        let source = "# comment\nhttp {\n  server { listen 80; server_name 'a' \"b\";}\n  \
+                      empty {}\n}\nlast;";
+        assert_eq!(
+            parse(source).unwrap().to_string(),
+            "http {\n    server {\n        listen 80;\n        server_name 'a' \"b\";\n    \
+             }\n    empty {\n    }\n}\nlast;\n"
+        );
+    }
+
+    #[test]
+    fn requotes_values() {
+        let cases = [
+            (ParameterType::Simple, "plain", "plain"),
+            (ParameterType::Simple, "two words", "\"two words\""),
+            (ParameterType::Simple, "a;b", "\"a;b\""),
+            (ParameterType::Simple, "{", "\"{\""),
+            (ParameterType::Simple, "", "\"\""),
+            (ParameterType::Simple, "#hash", "\"#hash\""),
+            (ParameterType::Simple, "ends\\", "\"ends\\\\\""),
+            (ParameterType::Simple, "~^/a\\.php$", "~^/a\\.php$"),
+            (ParameterType::Simple, "a\\nb", "\"a\\\\nb\""),
+            (ParameterType::SingleQuote, "it's", "'it\\'s'"),
+            (ParameterType::SingleQuote, "say \"hi\"", "'say \"hi\"'"),
+            (
+                ParameterType::DoubleQuote,
+                "say \"hi\"",
+                "\"say \\\"hi\\\"\"",
+            ),
+            (ParameterType::DoubleQuote, "cr\r\nlf", "\"cr\\r\nlf\""),
+        ];
+        for (parameter_type, value, expected) in &cases {
+            let written = parameter(*parameter_type, value).to_string();
+            assert_eq!(written, *expected);
+            let parsed = parse(&format!("a {};", written)).unwrap();
+            assert_eq!(parsed.directives[0].parameters[0].value, *value);
+        }
+    }
+
+    #[test]
+    fn generated_config_round_trips() {
+        let config = Block::new(vec![Directive::new(
+            "http",
+            Vec::new(),
+            Some(Block::new(vec![Directive::new(
+                "server",
+                Vec::new(),
+                Some(Block::new(vec![
+                    Directive::new("listen", vec![Parameter::new("127.0.0.1:80")], None),
+                    Directive::new(
+                        "return",
+                        vec![Parameter::new("200"), Parameter::new("hello; world")],
+                        None,
+                    ),
+                ])),
+            )])),
+        )]);
+        let text = config.to_string();
+        assert_eq!(
+            text,
+            "http {\n    server {\n        listen 127.0.0.1:80;\n        \
+             return 200 \"hello; world\";\n    }\n}\n"
+        );
+        assert_eq!(parse(&text).unwrap().to_string(), text);
+    }
+}
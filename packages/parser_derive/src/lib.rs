@@ -0,0 +1,313 @@
+//! `#[derive(FromBlock)]`, see `parser::schema` for the attributes.
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{quote, quote_spanned};
+use syn::{
+    parse_macro_input, spanned::Spanned, Data, DeriveInput, Error, Expr, Field, Fields, Lit,
+    LitStr, Path, Type,
+};
+
+#[proc_macro_derive(FromBlock, attributes(from_block, directive))]
+pub fn derive_from_block(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// How many parameters a directive with `args` takes.
+struct Args {
+    min: usize,
+    max: Option<usize>,
+}
+
+#[derive(Default)]
+struct DirectiveAttr {
+    name: Option<LitStr>,
+    default: Option<Expr>,
+    repeated: bool,
+    block: bool,
+    from_directive: bool,
+    skip: bool,
+    args: Option<Args>,
+    parse_with: Option<Path>,
+}
+
+fn parse_args(lit: &Lit) -> syn::Result<Args> {
+    let invalid = || {
+        Error::new(
+            lit.span(),
+            "expected a count like `2` or a range like \"1..3\"",
+        )
+    };
+    match lit {
+        Lit::Int(count) => {
+            let count = count.base10_parse()?;
+            Ok(Args {
+                min: count,
+                max: Some(count),
+            })
+        }
+        Lit::Str(range) => {
+            let value = range.value();
+            let (min, max) = value.split_once("..").ok_or_else(invalid)?;
+            let min = if min.is_empty() {
+                0
+            } else {
+                min.parse().map_err(|_| invalid())?
+            };
+            let max = match max.strip_prefix('=') {
+                Some(max) => Some(max.parse().map_err(|_| invalid())?),
+                None if max.is_empty() => None,
+                None => Some(max.parse::<usize>().map_err(|_| invalid())? - 1),
+            };
+            if max.is_some_and(|max| max < min) {
+                return Err(Error::new(lit.span(), "the range is empty"));
+            }
+            Ok(Args { min, max })
+        }
+        _ => Err(invalid()),
+    }
+}
+
+fn directive_attr(field: &Field) -> syn::Result<DirectiveAttr> {
+    let mut attr = DirectiveAttr::default();
+    for attribute in field
+        .attrs
+        .iter()
+        .filter(|a| a.path().is_ident("directive"))
+    {
+        attribute.parse_nested_meta(|meta| {
+            if meta.path.is_ident("name") {
+                attr.name = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("default") {
+                let value: LitStr = meta.value()?.parse()?;
+                attr.default = Some(value.parse()?);
+            } else if meta.path.is_ident("repeated") {
+                attr.repeated = true;
+            } else if meta.path.is_ident("block") {
+                attr.block = true;
+            } else if meta.path.is_ident("from_directive") {
+                attr.from_directive = true;
+            } else if meta.path.is_ident("skip") {
+                attr.skip = true;
+            } else if meta.path.is_ident("args") {
+                attr.args = Some(parse_args(&meta.value()?.parse()?)?);
+            } else if meta.path.is_ident("parse_with") {
+                let value: LitStr = meta.value()?.parse()?;
+                attr.parse_with = Some(value.parse()?);
+            } else {
+                return Err(meta.error(
+                    "expected `name`, `default`, `repeated`, `block`, `from_directive`, `skip`, \
+                     `args` or `parse_with`",
+                ));
+            }
+            Ok(())
+        })?;
+    }
+    let conversions = attr.block as u8
+        + attr.from_directive as u8
+        + attr.args.is_some() as u8
+        + attr.parse_with.is_some() as u8;
+    if conversions > 1 {
+        return Err(Error::new(
+            field.span(),
+            "only one of `block`, `from_directive`, `args` and `parse_with` can be used",
+        ));
+    }
+    Ok(attr)
+}
+
+/// Whether `ty` is `name<...>`, e.g. `Option<T>`, going by the last segment.
+fn is_wrapper(ty: &Type, name: &str) -> bool {
+    match ty {
+        Type::Path(path) if path.qself.is_none() => path
+            .path
+            .segments
+            .last()
+            .is_some_and(|segment| segment.ident == name),
+        _ => false,
+    }
+}
+
+/// The `#[from_block(...)]` attribute of the struct.
+struct StructAttr {
+    name: String,
+    validate: Option<Path>,
+}
+
+fn struct_attr(input: &DeriveInput) -> syn::Result<StructAttr> {
+    let mut attr = StructAttr {
+        name: input.ident.to_string().to_lowercase(),
+        validate: None,
+    };
+    for attribute in input
+        .attrs
+        .iter()
+        .filter(|a| a.path().is_ident("from_block"))
+    {
+        attribute.parse_nested_meta(|meta| {
+            if meta.path.is_ident("name") {
+                attr.name = meta.value()?.parse::<LitStr>()?.value();
+                Ok(())
+            } else if meta.path.is_ident("validate") {
+                attr.validate = Some(meta.value()?.parse::<LitStr>()?.parse()?);
+                Ok(())
+            } else {
+                Err(meta.error("expected `name` or `validate`"))
+            }
+        })?;
+    }
+    Ok(attr)
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(Error::new(
+                    input.ident.span(),
+                    "FromBlock needs a struct with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(Error::new(
+                input.ident.span(),
+                "FromBlock can only be derived for structs",
+            ))
+        }
+    };
+    let ident = &input.ident;
+    let StructAttr { name, validate } = struct_attr(input)?;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let mut names = Vec::new();
+    let mut lets = Vec::new();
+    let mut inits = Vec::new();
+    for field in fields {
+        let attr = directive_attr(field)?;
+        let field_ident = field.ident.as_ref().unwrap();
+        let ty = &field.ty;
+        if attr.skip {
+            inits.push(quote! { #field_ident: ::std::default::Default::default() });
+            continue;
+        }
+        let directive = match &attr.name {
+            Some(name) => name.value(),
+            None => field_ident.to_string().trim_start_matches("r#").to_string(),
+        };
+        if names.contains(&directive) {
+            return Err(Error::new(
+                field.span(),
+                format!("\"{}\" is used by another field", directive),
+            ));
+        }
+        let span = ty.span();
+        let convert = if attr.block {
+            quote_spanned! {span=> ::parser::schema::block(directive, problems) }
+        } else if attr.from_directive {
+            quote_spanned! {span=> ::parser::schema::from_directive(directive, problems) }
+        } else if let Some(path) = &attr.parse_with {
+            quote_spanned! {span=> ::parser::schema::parse_with(directive, problems, #path) }
+        } else if let Some(Args { min, max }) = &attr.args {
+            let max = match max {
+                Some(max) => quote! { ::std::option::Option::Some(#max) },
+                None => quote! { ::std::option::Option::None },
+            };
+            quote_spanned! {span=> ::parser::schema::parameters(directive, #min, #max, problems) }
+        } else {
+            if is_wrapper(ty, "Vec") && !attr.repeated {
+                return Err(Error::new(
+                    ty.span(),
+                    "list fields need `repeated` or `args`",
+                ));
+            }
+            quote_spanned! {span=> ::parser::schema::parameter(directive, problems) }
+        };
+        let value = if attr.repeated {
+            if !is_wrapper(ty, "Vec") {
+                return Err(Error::new(ty.span(), "`repeated` fields must be a `Vec`"));
+            }
+            let found = quote! {
+                ::parser::schema::find_all(block, #directive)
+                    .filter_map(|directive| #convert)
+                    .collect()
+            };
+            match &attr.default {
+                // used when the directive isn't there at all
+                Some(default) => quote! {
+                    let #field_ident: #ty =
+                        if ::parser::schema::find_all(block, #directive).next().is_none() {
+                            #default
+                        } else {
+                            #found
+                        };
+                },
+                None => quote! { let #field_ident: #ty = #found; },
+            }
+        } else {
+            let found = quote! {
+                ::parser::schema::find_one(block, #directive, problems)
+                    .and_then(|directive| #convert)
+            };
+            if is_wrapper(ty, "Option") {
+                quote! { let #field_ident: #ty = #found; }
+            } else if let Some(default) = &attr.default {
+                quote! {
+                    let #field_ident: #ty = #found.unwrap_or_else(|| #default);
+                }
+            } else {
+                quote! {
+                    let #field_ident: ::std::option::Option<#ty> =
+                        match ::parser::schema::find_one(block, #directive, problems) {
+                            ::std::option::Option::Some(directive) => #convert,
+                            ::std::option::Option::None => {
+                                ::parser::schema::missing(block, #name, #directive, problems);
+                                ::std::option::Option::None
+                            }
+                        };
+                }
+            }
+        };
+        let init = if attr.repeated || attr.default.is_some() || is_wrapper(ty, "Option") {
+            quote! { #field_ident }
+        } else {
+            quote! { #field_ident: #field_ident? }
+        };
+        names.push(directive);
+        lets.push(value);
+        inits.push(init);
+    }
+
+    let validate = validate.map(|path| {
+        quote! {
+            #path(&value, problems);
+            if problems.len() > before {
+                return ::std::option::Option::None;
+            }
+        }
+    });
+
+    Ok(quote! {
+        impl #impl_generics ::parser::schema::FromBlock for #ident #ty_generics #where_clause {
+            const NAME: &'static str = #name;
+
+            fn from_block(
+                block: &::parser::Block,
+                problems: &mut ::std::vec::Vec<::parser::schema::Problem>,
+            ) -> ::std::option::Option<Self> {
+                let before = problems.len();
+                ::parser::schema::unknown_directives(block, &[#(#names),*], problems);
+                #(#lets)*
+                if problems.len() > before {
+                    return ::std::option::Option::None;
+                }
+                let value = Self { #(#inits),* };
+                #validate
+                ::std::option::Option::Some(value)
+            }
+        }
+    })
+}
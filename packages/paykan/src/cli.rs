@@ -0,0 +1,99 @@
+//! Command line options, following nginx's flags.
+use std::path::PathBuf;
+
+pub const DEFAULT_CONFIG: &str = "/etc/paykan/paykan.conf";
+
+pub const USAGE: &str = "\
+Usage: paykan [-h] [-t] [-T] [-c filename]
+
+Options:
+  -h            : show this help and exit
+  -t            : test the configuration and exit
+  -T            : test the configuration, print it with includes expanded and exit
+  -c filename   : set the configuration file (default: /etc/paykan/paykan.conf)";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Serve,
+    Help,
+    Test,
+    Dump,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub mode: Mode,
+    pub config: PathBuf,
+}
+
+/// Parses the arguments after the program name.
+pub fn parse_args<I>(args: I) -> Result<Options, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut options = Options {
+        mode: Mode::Serve,
+        config: PathBuf::from(DEFAULT_CONFIG),
+    };
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "-?" => options.mode = Mode::Help,
+            "-t" if options.mode != Mode::Dump => options.mode = Mode::Test,
+            "-t" => {}
+            "-T" => options.mode = Mode::Dump,
+            "-c" => match args.next() {
+                Some(path) => options.config = PathBuf::from(path),
+                None => return Err("option \"-c\" requires file name".to_string()),
+            },
+            _ => match arg.strip_prefix("-c") {
+                Some(path) => options.config = PathBuf::from(path),
+                None => return Err(format!("invalid option: \"{}\"", arg)),
+            },
+        }
+    }
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn defaults() {
+        assert_eq!(
+            parse(&[]).unwrap(),
+            Options {
+                mode: Mode::Serve,
+                config: PathBuf::from(DEFAULT_CONFIG),
+            }
+        );
+    }
+
+    #[test]
+    fn flags() {
+        let options = parse(&["-c", "/tmp/a.conf", "-t"]).unwrap();
+        assert_eq!(options.mode, Mode::Test);
+        assert_eq!(options.config, PathBuf::from("/tmp/a.conf"));
+        assert_eq!(parse(&["-t", "-T"]).unwrap().mode, Mode::Dump);
+        assert_eq!(parse(&["-T", "-t"]).unwrap().mode, Mode::Dump);
+        assert_eq!(parse(&["-h"]).unwrap().mode, Mode::Help);
+        assert_eq!(
+            parse(&["-cconf/p.conf"]).unwrap().config,
+            PathBuf::from("conf/p.conf")
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(
+            parse(&["-c"]).unwrap_err(),
+            "option \"-c\" requires file name"
+        );
+        assert_eq!(parse(&["-x"]).unwrap_err(), "invalid option: \"-x\"");
+    }
+}
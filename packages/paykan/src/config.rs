@@ -1,68 +1,320 @@
-use parser::Block;
-use std::{net::SocketAddr, str::FromStr};
+pub mod access;
+pub mod env;
+pub mod files;
+pub mod keepalive;
+pub mod listen;
+pub mod location;
+pub mod server_name;
 
+pub use access::LimitExcept;
+pub use files::{DocumentRoot, Files, Flag};
+pub use keepalive::{KeepAlive, Time};
+pub use listen::Listen;
+pub use location::{Location, LocationPath};
+pub use parser::schema::Problem;
+pub use server_name::ServerName;
+
+use crate::variables::Template;
+use parser::{schema::FromBlock, Block, ParseError, Span};
+use std::{
+    collections::HashMap, convert::TryFrom, error::Error, fmt, net::SocketAddr, num::NonZeroU32,
+    path::Path, sync::Arc,
+};
+
+#[derive(FromBlock)]
 pub struct Config {
+    #[directive(block, default = "Events::default()")]
+    pub events: Events,
+    #[directive(block)]
     pub http: Http,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, FromBlock)]
+pub struct Events {
+    /// How many connections can be open at once, over all listeners. Once
+    /// they are, new connections wait in the backlog.
+    #[directive(default = "Events::default().worker_connections")]
+    pub worker_connections: NonZeroU32,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self {
+            worker_connections: NonZeroU32::new(512).unwrap(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, FromBlock)]
 pub struct Http {
+    #[directive(name = "server", repeated, block)]
     pub servers: Vec<Server>,
+    pub keepalive_timeout: Option<Time>,
+    pub keepalive_requests: Option<u32>,
+    /// Built from the `listen` directives of the servers.
+    #[directive(skip)]
+    pub listeners: Vec<Listener>,
+    /// `keepalive_timeout` and `keepalive_requests` with their defaults.
+    #[directive(skip)]
+    pub keepalive: KeepAlive,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, FromBlock)]
+#[from_block(validate = "Server::validate")]
 pub struct Server {
-    pub server_name: String,
-    pub listen: SocketAddr,
+    #[directive(args = "1..", default = "Vec::new()")]
+    pub server_name: Vec<ServerName>,
+    #[directive(
+        repeated,
+        parse_with = "listen::parse",
+        default = "vec![Listen::default()]"
+    )]
+    pub listen: Vec<Listen>,
+    #[directive(name = "location", repeated, from_directive)]
+    pub locations: Vec<Location>,
+    pub root: Option<Template>,
+    #[directive(args = "1..")]
+    pub index: Option<Vec<String>>,
+    pub disable_symlinks: Option<Flag>,
+    pub keepalive_timeout: Option<Time>,
+    pub keepalive_requests: Option<u32>,
+    /// `root`, `index` and `disable_symlinks` with their defaults.
+    #[directive(skip)]
+    pub files: Files,
+    /// `keepalive_timeout` and `keepalive_requests`, from `http` when unset.
+    #[directive(skip)]
+    pub keepalive: KeepAlive,
 }
 
-impl From<Block> for Config {
-    fn from(b: Block) -> Self {
-        let http = b.directives.iter().find(|x| x.name == "http").unwrap();
-        let servers: Vec<_> = http
-            .block
-            .as_ref()
-            .unwrap()
-            .directives
-            .iter()
-            .filter(|x| x.name == "server")
-            .map(|x| x.block.as_ref().unwrap())
-            .map(|x| Server {
-                server_name: x
-                    .directives
-                    .iter()
-                    .find(|y| y.name == "server_name")
-                    .unwrap()
-                    .parameters
-                    .first()
-                    .unwrap()
-                    .1
-                    .clone(),
-                listen: SocketAddr::from_str(
-                    &x.directives
-                        .iter()
-                        .find(|y| y.name == "listen")
-                        .unwrap()
-                        .parameters
-                        .first()
-                        .unwrap()
-                        .1,
-                )
-                .unwrap(),
-            })
-            .collect();
+impl Server {
+    /// The first `server_name`, which `$server_name` evaluates to.
+    pub fn name(&self) -> String {
+        self.server_name
+            .first()
+            .map(ToString::to_string)
+            .unwrap_or_default()
+    }
+
+    /// The location for `uri`, which shouldn't have a query string.
+    pub fn location(&self, uri: &str) -> Option<&Location> {
+        location::find(&self.locations, uri)
+    }
+
+    fn validate(&self, problems: &mut Vec<Problem>) {
+        location::validate(None, &self.locations, problems);
+    }
+}
+
+/// A socket shared by every server listening on its address.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listener {
+    pub address: SocketAddr,
+    /// Indexes into [`Http::servers`], in config order.
+    pub servers: Vec<usize>,
+    /// The server marked `default_server`, or the first one.
+    pub default_server: usize,
+    pub reuseport: bool,
+    pub backlog: Option<u32>,
+    pub ipv6only: Option<bool>,
+}
+
+/// Every problem found while building a [`Config`], in source order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    pub problems: Vec<Problem>,
+}
+
+impl Listener {
+    /// The server a request with the `host` header is for, the default server
+    /// when no `server_name` matches or the host is invalid.
+    pub fn server_for(&self, servers: &[Server], host: Option<&str>) -> usize {
+        match server_name::normalize_host(host.unwrap_or_default()) {
+            Some(host) => server_name::find(servers, &self.servers, &host),
+            None => None,
+        }
+        .unwrap_or(self.default_server)
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, problem) in self.problems.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ConfigError {}
 
+impl From<env::EnvError> for ConfigError {
+    fn from(error: env::EnvError) -> Self {
         Self {
-            http: Http { servers },
+            problems: vec![Problem::new(error.to_string(), &error.span)],
+        }
+    }
+}
+
+impl TryFrom<Block> for Config {
+    type Error = ConfigError;
+
+    fn try_from(block: Block) -> Result<Self, Self::Error> {
+        Self::try_from(&block)
+    }
+}
+
+impl TryFrom<&Block> for Config {
+    type Error = ConfigError;
+
+    /// Validates the whole config and reports every problem at once rather
+    /// than stopping at the first one.
+    fn try_from(block: &Block) -> Result<Self, Self::Error> {
+        let mut problems = Vec::new();
+        let mut config = Config::from_block(block, &mut problems);
+        if let Some(config) = &mut config {
+            config.http.listeners = listeners(&config.http.servers, &mut problems);
+            for server in &mut config.http.servers {
+                files::inherit_server(server);
+                access::inherit_server(server);
+            }
+            keepalive::inherit_http(&mut config.http);
+        }
+        match config {
+            Some(config) if problems.is_empty() => Ok(config),
+            _ => {
+                problems.sort_by(|a, b| {
+                    (&a.span.file, a.span.start.offset).cmp(&(&b.span.file, b.span.start.offset))
+                });
+                Err(ConfigError { problems })
+            }
+        }
+    }
+}
+
+/// Why [`load`] failed.
+#[derive(Debug)]
+pub enum LoadError {
+    /// `source` is the text of the file the error is in, as it was parsed,
+    /// if it could be read.
+    Parse {
+        error: ParseError,
+        source: Option<Arc<str>>,
+    },
+    Config(ConfigError),
+}
+
+impl fmt::Display for LoadError {
+    /// Parse errors are shown with the offending line when the file was read.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Parse { error, source } => match source {
+                Some(source) => f.write_str(error.render_snippet(source).trim_end()),
+                None => write!(f, "error: {}", error.kind),
+            },
+            LoadError::Config(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl Error for LoadError {}
+
+/// Reads the config at `path` with its includes, expands environment
+/// variables and validates it. The resolved block is returned along with the
+/// config so it can be printed.
+pub fn load(path: &Path) -> Result<(Block, Config), LoadError> {
+    let mut block = parser::parse_file(path).map_err(|error| {
+        // the error may be in an included file, whose text comes with it
+        let source = error.input.clone();
+        LoadError::Parse { error, source }
+    })?;
+    env::expand(&mut block, &|name| std::env::var(name).ok())
+        .map_err(|error| LoadError::Config(error.into()))?;
+    let config = Config::try_from(&block).map_err(LoadError::Config)?;
+    Ok((block, config))
+}
+
+/// Groups the `listen` directives of all servers by address. Reports a
+/// server listening twice on the same address, and more than one
+/// `default_server` or set of socket options for an address.
+fn listeners(servers: &[Server], problems: &mut Vec<Problem>) -> Vec<Listener> {
+    let mut listeners: Vec<Listener> = Vec::new();
+    // the spans of the `default_server` and socket options of each listener
+    let mut set_at: Vec<(Option<&Span>, Option<&Span>)> = Vec::new();
+    for (index, server) in servers.iter().enumerate() {
+        let mut seen: HashMap<SocketAddr, &Span> = HashMap::new();
+        for listen in &server.listen {
+            for &address in &listen.addresses {
+                if let Some(first) = seen.insert(address, &listen.span) {
+                    problems.push(Problem::new(
+                        format!("duplicate listen {}, already used at {}", address, first),
+                        &listen.span,
+                    ));
+                    continue;
+                }
+                let position = match listeners.iter().position(|l| l.address == address) {
+                    Some(position) => position,
+                    None => {
+                        listeners.push(Listener {
+                            address,
+                            servers: Vec::new(),
+                            default_server: index,
+                            reuseport: false,
+                            backlog: None,
+                            ipv6only: None,
+                        });
+                        set_at.push((None, None));
+                        listeners.len() - 1
+                    }
+                };
+                let listener = &mut listeners[position];
+                let (default_at, options_at) = &mut set_at[position];
+                listener.servers.push(index);
+                if listen.default_server {
+                    match default_at {
+                        Some(first) => problems.push(Problem::new(
+                            format!(
+                                "duplicate default server for {}, already set at {}",
+                                address, first
+                            ),
+                            &listen.span,
+                        )),
+                        None => {
+                            listener.default_server = index;
+                            *default_at = Some(&listen.span);
+                        }
+                    }
+                }
+                if listen.has_socket_options() {
+                    match options_at {
+                        Some(first) => problems.push(Problem::new(
+                            format!(
+                                "duplicate listen options for {}, already set at {}",
+                                address, first
+                            ),
+                            &listen.span,
+                        )),
+                        None => {
+                            listener.reuseport = listen.reuseport;
+                            listener.backlog = listen.backlog;
+                            listener.ipv6only = listen.ipv6only;
+                            *options_at = Some(&listen.span);
+                        }
+                    }
+                }
+            }
         }
     }
+    listeners
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
     use parser::parse;
-    use std::net::SocketAddr;
+    use std::{fs, net::SocketAddr, str::FromStr};
 
     #[test]
     fn test_config() {
@@ -79,18 +331,194 @@ mod test {
             }
         }
         "#,
-        );
-        let conf = Config::from(config);
+        )
+        .unwrap();
+        let conf = Config::try_from(config).unwrap();
         assert_eq!(conf.http.servers.len(), 2);
-        assert_eq!(conf.http.servers[0].server_name, "server_name");
+        assert_eq!(conf.http.servers[0].name(), "server_name");
+        assert_eq!(
+            conf.http.servers[0].listen[0].addresses,
+            [SocketAddr::from_str("127.0.0.1:8080").unwrap()]
+        );
+        assert_eq!(conf.http.servers[1].name(), "server_name2");
+        assert_eq!(
+            conf.http.servers[1].listen[0].addresses,
+            [SocketAddr::from_str("127.0.0.1:8081").unwrap()]
+        );
+    }
+
+    fn problems(source: &str) -> Vec<(usize, String)> {
+        let error = Config::try_from(parse(source).unwrap()).err().unwrap();
+        error
+            .problems
+            .into_iter()
+            .map(|p| (p.span.start.line, p.message))
+            .collect()
+    }
+
+    #[test]
+    fn missing_http() {
+        assert_eq!(
+            problems("events {}"),
+            vec![(1, "\"config\" is missing a \"http\" directive".to_string())]
+        );
+    }
+
+    #[test]
+    fn parses_events() {
+        let config = |source: &str| Config::try_from(parse(source).unwrap()).unwrap();
+        let events = config("http {}").events;
+        assert_eq!(events.worker_connections.get(), 512);
+        let events = config("events { worker_connections 2; } http {}").events;
+        assert_eq!(events.worker_connections.get(), 2);
         assert_eq!(
-            conf.http.servers[0].listen,
-            SocketAddr::from_str("127.0.0.1:8080").unwrap()
+            problems("events { worker_connections 0; use epoll; } http {}"),
+            vec![
+                (
+                    1,
+                    "invalid value \"0\" in \"worker_connections\" directive: \
+                     number would be zero for non-zero type"
+                        .to_string()
+                ),
+                (1, "unknown directive \"use\"".to_string()),
+            ]
         );
-        assert_eq!(conf.http.servers[1].server_name, "server_name2");
+    }
+
+    #[test]
+    fn collects_all_problems() {
+        let source = r#"
+        http {
+            server {
+                server_name;
+            }
+            server {
+                server_name a *b;
+                listen 127.0.0.1:http;
+                gzip on;
+            }
+            server {
+                server_name c;
+                listen 127.0.0.1:8080;
+                listen 127.0.0.1:8081;
+            }
+            server;
+            upstream {}
+        }
+        "#;
         assert_eq!(
-            conf.http.servers[1].listen,
-            SocketAddr::from_str("127.0.0.1:8081").unwrap()
+            problems(source),
+            vec![
+                (
+                    4,
+                    "\"server_name\" directive takes at least 1 argument".to_string()
+                ),
+                (
+                    7,
+                    "invalid value \"*b\" in \"server_name\" directive: \
+                     invalid server name or wildcard \"*b\""
+                        .to_string()
+                ),
+                (
+                    8,
+                    "invalid port in \"127.0.0.1:http\" of the \"listen\" directive".to_string()
+                ),
+                (9, "unknown directive \"gzip\"".to_string()),
+                (16, "\"server\" directive requires a block".to_string()),
+                (17, "unknown directive \"upstream\"".to_string()),
+            ]
         );
     }
+
+    #[test]
+    fn groups_listeners() {
+        let source = r#"
+        http {
+            server {
+                server_name a;
+                listen 8080;
+                listen 127.0.0.1:8081 reuseport;
+            }
+            server {
+                server_name b;
+                listen *:8080 default_server backlog=64;
+            }
+            server {
+                server_name c;
+            }
+        }
+        "#;
+        let config = Config::try_from(parse(source).unwrap()).unwrap();
+        let listeners = config.http.listeners;
+        let address = |a: &str| SocketAddr::from_str(a).unwrap();
+        assert_eq!(listeners.len(), 3);
+        assert_eq!(listeners[0].address, address("0.0.0.0:8080"));
+        assert_eq!(listeners[0].servers, [0, 1]);
+        assert_eq!(listeners[0].default_server, 1);
+        assert_eq!(listeners[0].backlog, Some(64));
+        assert_eq!(listeners[1].address, address("127.0.0.1:8081"));
+        assert!(listeners[1].reuseport);
+        assert_eq!(listeners[2].address, address("0.0.0.0:80"));
+        assert_eq!(listeners[2].servers, [2]);
+
+        let servers = &config.http.servers;
+        assert_eq!(listeners[0].server_for(servers, Some("A:8080")), 0);
+        assert_eq!(listeners[0].server_for(servers, Some("c")), 1);
+        assert_eq!(listeners[0].server_for(servers, None), 1);
+        assert_eq!(listeners[1].server_for(servers, Some("b")), 0);
+    }
+
+    #[test]
+    fn conflicting_listens() {
+        let source = r#"
+        http {
+            server {
+                server_name a;
+                listen 8080 default_server backlog=10;
+                listen *:8080;
+            }
+            server {
+                server_name b;
+                listen 8080 default_server reuseport;
+            }
+        }
+        "#;
+        assert_eq!(
+            problems(source),
+            vec![
+                (
+                    6,
+                    "duplicate listen 0.0.0.0:8080, already used at line 5, column 17".to_string()
+                ),
+                (
+                    10,
+                    "duplicate default server for 0.0.0.0:8080, already set at line 5, column 17"
+                        .to_string()
+                ),
+                (
+                    10,
+                    "duplicate listen options for 0.0.0.0:8080, already set at line 5, column 17"
+                        .to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn load_error_keeps_the_source() {
+        let path = std::env::temp_dir().join(format!("paykan-load-{}.conf", std::process::id()));
+        fs::write(&path, "http {\n    hello 'test;\n}").unwrap();
+        let error = load(&path).err().unwrap();
+        // rendered from the text that was parsed, not from the file now
+        fs::write(&path, "http {\n    changed;\n}").unwrap();
+        let rendered = error.to_string();
+        assert!(rendered.starts_with("error: unterminated quoted parameter\n"));
+        assert!(rendered.ends_with("2 |     hello 'test;\n  |           ^"));
+        fs::remove_file(&path).unwrap();
+        assert!(load(&path)
+            .err()
+            .unwrap()
+            .to_string()
+            .starts_with("error: "));
+    }
 }
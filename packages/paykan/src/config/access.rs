@@ -0,0 +1,302 @@
+//! `limit_except`, which limits the methods a location accepts. Requests with
+//! a listed method pass, the others go through the `allow` and `deny` rules
+//! of its block in order, and the first rule matching the client decides.
+//! Allowing `GET` also allows `HEAD`, as in nginx. Nested locations inherit
+//! the `limit_except` of the enclosing location when they don't set one.
+use super::{Location, Server};
+use crate::lazy_stream_reader::HttpMethod;
+use parser::{
+    schema::{FromBlock, FromDirective, Problem},
+    Directive,
+};
+use std::{net::IpAddr, str::FromStr};
+
+/// The clients an `allow` or `deny` rule applies to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Network {
+    All,
+    /// An address with the bits after `prefix` cleared.
+    Cidr {
+        address: IpAddr,
+        prefix: u8,
+    },
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value == "all" {
+            return Ok(Network::All);
+        }
+        let invalid = || "it must be \"all\", an address or a CIDR block".to_string();
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value, None),
+        };
+        let address: IpAddr = address.parse().map_err(|_| invalid())?;
+        let bits = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) if !prefix.is_empty() && prefix.bytes().all(|b| b.is_ascii_digit()) => {
+                prefix
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|&prefix| prefix <= bits)
+                    .ok_or_else(invalid)?
+            }
+            Some(_) => return Err(invalid()),
+            None => bits,
+        };
+        Ok(Network::Cidr {
+            address: mask(address, prefix),
+            prefix,
+        })
+    }
+}
+
+/// `address` with the bits after `prefix` cleared.
+fn mask(address: IpAddr, prefix: u8) -> IpAddr {
+    match address {
+        IpAddr::V4(address) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            IpAddr::V4((u32::from(address) & mask).into())
+        }
+        IpAddr::V6(address) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            IpAddr::V6((u128::from(address) & mask).into())
+        }
+    }
+}
+
+impl Network {
+    pub fn contains(&self, client: IpAddr) -> bool {
+        match *self {
+            Network::All => true,
+            Network::Cidr { address, prefix } => {
+                // IPv4 clients of an IPv6 socket arrive as ::ffff:a.b.c.d
+                let client = match client {
+                    IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(client, IpAddr::V4),
+                    client => client,
+                };
+                client.is_ipv4() == address.is_ipv4() && mask(client, prefix) == address
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AccessRule {
+    pub allow: bool,
+    pub network: Network,
+}
+
+#[derive(Debug, Clone, FromBlock)]
+#[from_block(name = "limit_except")]
+pub struct LimitExcept {
+    /// Set from the parameters of the directive.
+    #[directive(skip)]
+    pub methods: Vec<HttpMethod>,
+    #[directive(repeated)]
+    pub allow: Vec<Network>,
+    #[directive(repeated)]
+    pub deny: Vec<Network>,
+    /// `allow` and `deny` in the order they were written.
+    #[directive(skip)]
+    pub rules: Vec<AccessRule>,
+}
+
+impl FromDirective for LimitExcept {
+    fn from_directive(directive: &Directive, problems: &mut Vec<Problem>) -> Option<Self> {
+        let before = problems.len();
+        if directive.parameters.is_empty() {
+            problems.push(Problem::new(
+                "invalid number of arguments in \"limit_except\" directive",
+                &directive.span,
+            ));
+        }
+        let mut methods = Vec::with_capacity(directive.parameters.len());
+        for parameter in &directive.parameters {
+            // methods are case-insensitive here, as in nginx
+            match parameter.value.to_ascii_uppercase().parse() {
+                Ok(method) => methods.push(method),
+                Err(_) => problems.push(Problem::new(
+                    format!("invalid method \"{}\"", parameter.value),
+                    &parameter.span,
+                )),
+            }
+        }
+        let block = match &directive.block {
+            Some(block) => block,
+            None => {
+                problems.push(Problem::new(
+                    "\"limit_except\" directive requires a block",
+                    &directive.span,
+                ));
+                return None;
+            }
+        };
+        let limit = LimitExcept::from_block(block, problems)?;
+        if problems.len() > before {
+            return None;
+        }
+        // find_all keeps the order of each directive, so walking the block
+        // interleaves them again
+        let (mut allow, mut deny) = (limit.allow.iter(), limit.deny.iter());
+        let rules = block
+            .directives
+            .iter()
+            .filter_map(|directive| match directive.name.as_str() {
+                "allow" => allow.next().map(|&network| (true, network)),
+                "deny" => deny.next().map(|&network| (false, network)),
+                _ => None,
+            })
+            .map(|(allow, network)| AccessRule { allow, network })
+            .collect();
+        Some(LimitExcept {
+            methods,
+            rules,
+            ..limit
+        })
+    }
+}
+
+impl LimitExcept {
+    /// Whether a request with `method` from `client` may go on.
+    pub fn permits(&self, method: &HttpMethod, client: IpAddr) -> bool {
+        let listed = self.methods.contains(method)
+            || (*method == HttpMethod::Head && self.methods.contains(&HttpMethod::Get));
+        listed
+            || self
+                .rules
+                .iter()
+                .find(|rule| rule.network.contains(client))
+                .is_none_or(|rule| rule.allow)
+    }
+}
+
+fn inherit_locations(parent: Option<&LimitExcept>, locations: &mut [Location]) {
+    for location in locations {
+        if location.limit_except.is_none() {
+            location.limit_except = parent.cloned();
+        }
+        inherit_locations(location.limit_except.as_ref(), &mut location.locations);
+    }
+}
+
+/// Fills in the `limit_except` of nested locations that don't set one.
+pub fn inherit_server(server: &mut Server) {
+    inherit_locations(None, &mut server.locations);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn parses_networks() {
+        let cases = [
+            ("all", Some("All")),
+            ("10.1.2.3", Some("10.1.2.3/32")),
+            ("10.1.2.3/8", Some("10.0.0.0/8")),
+            ("0.0.0.0/0", Some("0.0.0.0/0")),
+            ("2001:db8::1/32", Some("2001:db8::/32")),
+            ("::1", Some("::1/128")),
+            ("10.0.0.0/33", None),
+            ("10.0.0.0/+8", None),
+            ("10.0.0.0/", None),
+            ("example.com", None),
+        ];
+        for (value, expected) in &cases {
+            let network = value.parse::<Network>().ok().map(|network| match network {
+                Network::All => "All".to_string(),
+                Network::Cidr { address, prefix } => format!("{}/{}", address, prefix),
+            });
+            assert_eq!(network.as_deref(), *expected, "{}", value);
+        }
+    }
+
+    #[test]
+    fn limits_methods() {
+        let source = r#"
+        http {
+            server {
+                location / {
+                    limit_except get Propfind {
+                        allow 10.0.0.0/8;
+                        allow ::1;
+                        deny 10.1.0.0/16;
+                        deny all;
+                    }
+                }
+                location /open/ { limit_except POST { } }
+                location /admin/ {
+                    limit_except GET { deny all; }
+                    location /admin/up/ { }
+                    location /admin/open/ { limit_except POST { } }
+                }
+            }
+        }
+        "#;
+        let config = Config::try_from(parser::parse(source).unwrap()).unwrap();
+        let server = &config.http.servers[0];
+        let limit = server.locations[0].limit_except.as_ref().unwrap();
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let propfind = HttpMethod::Extension("PROPFIND".to_string());
+        for method in &[HttpMethod::Get, HttpMethod::Head, propfind] {
+            assert!(limit.permits(method, ip("192.168.0.1")));
+        }
+        assert!(!limit.permits(&HttpMethod::Post, ip("192.168.0.1")));
+        // the first matching rule wins
+        assert!(limit.permits(&HttpMethod::Post, ip("10.1.0.1")));
+        assert!(limit.permits(&HttpMethod::Delete, ip("::ffff:10.2.0.1")));
+        assert!(limit.permits(&HttpMethod::Delete, ip("::1")));
+        assert!(!limit.permits(&HttpMethod::Delete, ip("::2")));
+
+        let open = server.locations[1].limit_except.as_ref().unwrap();
+        assert!(open.permits(&HttpMethod::Delete, ip("192.168.0.1")));
+
+        // nested locations inherit the limit unless they set their own
+        let admin = &server.locations[2].locations;
+        let limit = server
+            .location("/admin/up/x")
+            .unwrap()
+            .limit_except
+            .as_ref();
+        assert!(!limit.unwrap().permits(&HttpMethod::Post, ip("192.168.0.1")));
+        assert!(limit.unwrap().permits(&HttpMethod::Get, ip("192.168.0.1")));
+        let open = admin[1].limit_except.as_ref().unwrap();
+        assert!(open.permits(&HttpMethod::Post, ip("192.168.0.1")));
+    }
+
+    #[test]
+    fn rejects_invalid_limits() {
+        let source = r#"
+        http {
+            server {
+                location /a/ { limit_except { deny all; } }
+                location /b/ { limit_except GET; }
+                location /c/ { limit_except G(T { deny all; } }
+                location /d/ { limit_except GET { deny nobody; root /x; } }
+            }
+        }
+        "#;
+        let problems = Config::try_from(parser::parse(source).unwrap())
+            .err()
+            .unwrap()
+            .problems;
+        let messages: Vec<_> = problems.iter().map(|p| p.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "invalid number of arguments in \"limit_except\" directive",
+                "\"limit_except\" directive requires a block",
+                "invalid method \"G(T\"",
+                "invalid value \"nobody\" in \"deny\" directive: \
+                 it must be \"all\", an address or a CIDR block",
+                "unknown directive \"root\"",
+            ]
+        );
+    }
+}
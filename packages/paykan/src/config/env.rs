@@ -0,0 +1,151 @@
+//! `${env:NAME}` and `${env:NAME:-default}` in parameters, replaced with the
+//! value of the environment variable when the config is loaded so the same
+//! file can be deployed to hosts that differ in ports, addresses or paths.
+use parser::{Block, Span};
+use std::{error::Error, fmt};
+
+const PREFIX: &str = "${env:";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EnvErrorKind {
+    /// The variable isn't set and the expansion has no default.
+    Missing(String),
+    /// `${env:` without the closing `}`.
+    Unterminated,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnvError {
+    pub kind: EnvErrorKind,
+    /// The parameter containing the expansion.
+    pub span: Span,
+}
+
+impl fmt::Display for EnvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            EnvErrorKind::Missing(name) => write!(
+                f,
+                "environment variable {} used at {} is not set",
+                name, self.span
+            ),
+            EnvErrorKind::Unterminated => {
+                write!(f, "missing '}}' after \"{}\" at {}", PREFIX, self.span)
+            }
+        }
+    }
+}
+
+impl Error for EnvError {}
+
+/// Replaces the expansions in every parameter of `block` and its nested
+/// blocks, looking variables up with `lookup`. `${env:NAME:-default}` uses
+/// the default when the variable is unset or empty.
+pub fn expand<F>(block: &mut Block, lookup: &F) -> Result<(), EnvError>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    for directive in &mut block.directives {
+        for parameter in &mut directive.parameters {
+            if parameter.value.contains(PREFIX) {
+                parameter.value =
+                    expand_value(&parameter.value, lookup).map_err(|kind| EnvError {
+                        kind,
+                        span: parameter.span.clone(),
+                    })?;
+            }
+        }
+        if let Some(inner) = &mut directive.block {
+            expand(inner, lookup)?;
+        }
+    }
+    Ok(())
+}
+
+fn expand_value<F>(value: &str, lookup: &F) -> Result<String, EnvErrorKind>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(index) = rest.find(PREFIX) {
+        result.push_str(&rest[..index]);
+        let expansion = &rest[index + PREFIX.len()..];
+        let end = expansion.find('}').ok_or(EnvErrorKind::Unterminated)?;
+        let (name, default) = match expansion[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&expansion[..end], None),
+        };
+        match (lookup(name).filter(|v| !v.is_empty()), default) {
+            (Some(value), _) => result.push_str(&value),
+            (None, Some(default)) => result.push_str(default),
+            (None, None) if lookup(name).is_some() => {}
+            (None, None) => return Err(EnvErrorKind::Missing(name.to_string())),
+        }
+        rest = &expansion[end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "PAYKAN_PORT" => Some("9090".to_string()),
+            "PAYKAN_HOST" => Some("10.0.0.1".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn expands_parameters() {
+        let mut block = parser::parse(
+            r#"
+            http {
+                server {
+                    listen ${env:PAYKAN_HOST}:${env:PAYKAN_PORT:-8080};
+                    server_name "${env:PAYKAN_NAME:-default.local}" plain;
+                    root /srv/${env:EMPTY:-www}/${env:EMPTY};
+                }
+            }
+            "#,
+        )
+        .unwrap();
+        expand(&mut block, &lookup).unwrap();
+        let server = block.directives[0].block.as_ref().unwrap().directives[0]
+            .block
+            .as_ref()
+            .unwrap();
+        let values: Vec<_> = server
+            .directives
+            .iter()
+            .flat_map(|d| d.parameters.iter().map(|p| p.value.as_str()))
+            .collect();
+        assert_eq!(
+            values,
+            vec!["10.0.0.1:9090", "default.local", "plain", "/srv/www/"]
+        );
+    }
+
+    #[test]
+    fn missing_variable() {
+        let mut block = parser::parse("a;\nlisten ${env:PAYKAN_LISTEN};").unwrap();
+        let error = expand(&mut block, &lookup).unwrap_err();
+        assert_eq!(
+            error.kind,
+            EnvErrorKind::Missing("PAYKAN_LISTEN".to_string())
+        );
+        assert_eq!(
+            error.to_string(),
+            "environment variable PAYKAN_LISTEN used at line 2, column 8 is not set"
+        );
+
+        let mut block = parser::parse("listen ${env:PAYKAN_PORT;").unwrap();
+        let error = expand(&mut block, &lookup).unwrap_err();
+        assert_eq!(error.kind, EnvErrorKind::Unterminated);
+    }
+}
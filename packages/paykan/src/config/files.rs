@@ -0,0 +1,144 @@
+//! `root`, `alias`, `index` and `disable_symlinks`, which say where the files
+//! of a server or location are. Locations inherit them from the enclosing
+//! location or server when they don't set them.
+use super::{Location, Server};
+use crate::variables::Template;
+use std::str::FromStr;
+
+/// nginx's default `root`, relative to the working directory.
+const DEFAULT_ROOT: &str = "html";
+const DEFAULT_INDEX: &str = "index.html";
+
+/// An `on` or `off` parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Flag(pub bool);
+
+impl FromStr for Flag {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "on" => Ok(Flag(true)),
+            "off" => Ok(Flag(false)),
+            _ => Err("it must be \"on\" or \"off\"".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum DocumentRoot {
+    /// `root`, the whole uri is appended to it.
+    Root(Template),
+    /// `alias`, the part of the uri after the location prefix is appended.
+    Alias(Template),
+}
+
+/// The file settings in effect for a server or location.
+#[derive(Debug, Clone)]
+pub struct Files {
+    pub root: DocumentRoot,
+    pub index: Vec<String>,
+    /// Refuse paths that go through a symbolic link below the root.
+    pub disable_symlinks: bool,
+}
+
+impl Default for Files {
+    fn default() -> Self {
+        Self {
+            root: DocumentRoot::Root(DEFAULT_ROOT.parse().unwrap()),
+            index: vec![DEFAULT_INDEX.to_string()],
+            disable_symlinks: false,
+        }
+    }
+}
+
+fn inherit(
+    parent: &Files,
+    root: &Option<Template>,
+    alias: &Option<Template>,
+    index: &Option<Vec<String>>,
+    disable_symlinks: Option<Flag>,
+) -> Files {
+    Files {
+        root: match (root, alias) {
+            (Some(root), _) => DocumentRoot::Root(root.clone()),
+            (_, Some(alias)) => DocumentRoot::Alias(alias.clone()),
+            _ => parent.root.clone(),
+        },
+        index: index.clone().unwrap_or_else(|| parent.index.clone()),
+        disable_symlinks: disable_symlinks.map_or(parent.disable_symlinks, |flag| flag.0),
+    }
+}
+
+fn inherit_locations(parent: &Files, locations: &mut [Location]) {
+    for location in locations {
+        location.files = inherit(
+            parent,
+            &location.root,
+            &location.alias,
+            &location.index,
+            location.disable_symlinks,
+        );
+        inherit_locations(&location.files.clone(), &mut location.locations);
+    }
+}
+
+/// Fills in the [`Files`] of the server and its locations.
+pub fn inherit_server(server: &mut Server) {
+    server.files = inherit(
+        &Files::default(),
+        &server.root,
+        &None,
+        &server.index,
+        server.disable_symlinks,
+    );
+    inherit_locations(&server.files.clone(), &mut server.locations);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::convert::TryFrom;
+
+    fn root(files: &Files) -> String {
+        match &files.root {
+            DocumentRoot::Root(root) => format!("root {:?}", root.segments),
+            DocumentRoot::Alias(alias) => format!("alias {:?}", alias.segments),
+        }
+    }
+
+    #[test]
+    fn locations_inherit_files() {
+        let source = r#"
+        http {
+            server {
+                root /srv;
+                index index.htm;
+                location /a/ {
+                    disable_symlinks on;
+                    location /a/b/ { alias /data/; index a b; }
+                    location /a/c/ { }
+                }
+            }
+            server { }
+        }
+        "#;
+        let config = Config::try_from(parser::parse(source).unwrap()).unwrap();
+        let server = &config.http.servers[0];
+        let a = &server.locations[0];
+        assert_eq!(root(&server.files), "root [Literal(\"/srv\")]");
+        assert_eq!(root(&a.files), "root [Literal(\"/srv\")]");
+        assert_eq!(a.files.index, ["index.htm"]);
+        assert!(a.files.disable_symlinks && !server.files.disable_symlinks);
+        let b = &a.locations[0];
+        assert_eq!(root(&b.files), "alias [Literal(\"/data/\")]");
+        assert_eq!(b.files.index, ["a", "b"]);
+        assert!(b.files.disable_symlinks);
+        assert_eq!(root(&a.locations[1].files), "root [Literal(\"/srv\")]");
+
+        let other = &config.http.servers[1];
+        assert_eq!(root(&other.files), "root [Literal(\"html\")]");
+        assert_eq!(other.files.index, ["index.html"]);
+    }
+}
@@ -0,0 +1,177 @@
+//! `keepalive_timeout` and `keepalive_requests`, which say how long and for
+//! how many requests a connection is kept open. Both can be set in `http`
+//! and in `server`, a server inherits the ones it doesn't set.
+use super::{Http, Server};
+use std::{str::FromStr, time::Duration};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(75);
+const DEFAULT_REQUESTS: u32 = 1000;
+
+/// A time like `75s`, `500ms` or `1m30s`, seconds without a unit. The units
+/// are `ms`, `s`, `m`, `h`, `d`, `w`, `M` (30 days) and `y` (365 days), each
+/// used once and from the largest to the smallest.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Time(pub Duration);
+
+impl FromStr for Time {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || "it must be a time like 75s, 500ms or 1m30s".to_string();
+        const UNITS: [(&str, u64); 8] = [
+            ("y", 365 * 24 * 60 * 60 * 1000),
+            ("M", 30 * 24 * 60 * 60 * 1000),
+            ("w", 7 * 24 * 60 * 60 * 1000),
+            ("d", 24 * 60 * 60 * 1000),
+            ("h", 60 * 60 * 1000),
+            ("m", 60 * 1000),
+            ("s", 1000),
+            ("ms", 1),
+        ];
+        if value.is_empty() {
+            return Err(invalid());
+        }
+        let mut rest = value;
+        let mut millis: u64 = 0;
+        // the units not used yet, smaller than the last one
+        let mut units = &UNITS[..];
+        while !rest.is_empty() {
+            let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+            if digits == 0 {
+                return Err(invalid());
+            }
+            let number: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+            rest = &rest[digits..];
+            let unit = rest.bytes().take_while(u8::is_ascii_alphabetic).count();
+            let name = match &rest[..unit] {
+                "" => "s",
+                name => name,
+            };
+            let index = units
+                .iter()
+                .position(|(unit, _)| *unit == name)
+                .ok_or_else(invalid)?;
+            let scale = units[index].1;
+            units = &units[index + 1..];
+            rest = &rest[unit..];
+            millis = number
+                .checked_mul(scale)
+                .and_then(|part| millis.checked_add(part))
+                .ok_or_else(invalid)?;
+        }
+        Ok(Time(Duration::from_millis(millis)))
+    }
+}
+
+/// The keep-alive settings in effect for a server.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeepAlive {
+    /// How long an idle connection waits for the next request, zero turns
+    /// keep-alive off.
+    pub timeout: Duration,
+    /// How many requests a connection serves before it's closed.
+    pub requests: u32,
+}
+
+impl Default for KeepAlive {
+    fn default() -> Self {
+        Self {
+            timeout: DEFAULT_TIMEOUT,
+            requests: DEFAULT_REQUESTS,
+        }
+    }
+}
+
+fn inherit(parent: &KeepAlive, timeout: Option<Time>, requests: Option<u32>) -> KeepAlive {
+    KeepAlive {
+        timeout: timeout.map_or(parent.timeout, |time| time.0),
+        requests: requests.unwrap_or(parent.requests),
+    }
+}
+
+/// Fills in [`Http::keepalive`] and the one of every server.
+pub fn inherit_http(http: &mut Http) {
+    http.keepalive = inherit(
+        &KeepAlive::default(),
+        http.keepalive_timeout,
+        http.keepalive_requests,
+    );
+    for server in &mut http.servers {
+        inherit_server(&http.keepalive, server);
+    }
+}
+
+fn inherit_server(parent: &KeepAlive, server: &mut Server) {
+    server.keepalive = inherit(parent, server.keepalive_timeout, server.keepalive_requests);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn parses_times() {
+        let cases = [
+            ("75", Some(75_000)),
+            ("75s", Some(75_000)),
+            ("500ms", Some(500)),
+            ("1m30s", Some(90_000)),
+            ("1h1ms", Some(3_600_001)),
+            ("1d", Some(86_400_000)),
+            ("0", Some(0)),
+            ("1M1w", Some(37 * 86_400_000)),
+            ("", None),
+            ("s", None),
+            ("1s1m", None),
+            ("1m1m", None),
+            ("30s1", None),
+            ("1x", None),
+            ("-1s", None),
+            ("1 s", None),
+            ("99999999999y", None),
+        ];
+        for (value, expected) in &cases {
+            let time = value.parse::<Time>().ok();
+            let millis = time.map(|time| time.0.as_millis() as u64);
+            assert_eq!(millis, *expected, "{}", value);
+        }
+    }
+
+    #[test]
+    fn inherits_settings() {
+        let source = r#"
+        http {
+            keepalive_timeout 10s;
+            server { }
+            server { keepalive_timeout 0; keepalive_requests 5; }
+        }
+        "#;
+        let config = Config::try_from(parser::parse(source).unwrap()).unwrap();
+        let servers = &config.http.servers;
+        assert_eq!(servers[0].keepalive.timeout, Duration::from_secs(10));
+        assert_eq!(servers[0].keepalive.requests, 1000);
+        assert_eq!(servers[1].keepalive.timeout, Duration::ZERO);
+        assert_eq!(servers[1].keepalive.requests, 5);
+
+        let config = Config::try_from(parser::parse("http { server { } }").unwrap()).unwrap();
+        assert_eq!(config.http.servers[0].keepalive, KeepAlive::default());
+
+        let source = "http { keepalive_timeout 1x; keepalive_requests -1; }";
+        let problems = Config::try_from(parser::parse(source).unwrap())
+            .err()
+            .unwrap()
+            .problems;
+        let messages: Vec<_> = problems.iter().map(|p| p.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "invalid value \"1x\" in \"keepalive_timeout\" directive: \
+                 it must be a time like 75s, 500ms or 1m30s",
+                "invalid value \"-1\" in \"keepalive_requests\" directive: \
+                 invalid digit found in string",
+            ]
+        );
+    }
+}
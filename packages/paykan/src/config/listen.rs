@@ -0,0 +1,295 @@
+//! The `listen` directive: `listen address[:port] [default_server] [ssl]
+//! [http2] [reuseport] [backlog=N] [ipv6only=on|off];`. The address can be a
+//! port alone, an IPv4 or `[IPv6]` address, `*` or a host name, which is
+//! resolved when the config is loaded.
+use parser::{Directive, Span};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+
+const DEFAULT_PORT: u16 = 80;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listen {
+    /// Every address the host resolved to, usually just one.
+    pub addresses: Vec<SocketAddr>,
+    pub default_server: bool,
+    pub ssl: bool,
+    pub http2: bool,
+    pub reuseport: bool,
+    pub backlog: Option<u32>,
+    pub ipv6only: Option<bool>,
+    pub span: Span,
+}
+
+impl Default for Listen {
+    /// `listen *:80;`, used by servers without a `listen` directive.
+    fn default() -> Self {
+        Self {
+            addresses: vec![SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                DEFAULT_PORT,
+            )],
+            default_server: false,
+            ssl: false,
+            http2: false,
+            reuseport: false,
+            backlog: None,
+            ipv6only: None,
+            span: Span::default(),
+        }
+    }
+}
+
+impl Listen {
+    /// Whether this sets options of the socket itself. Only one `listen` per
+    /// address may do that since all servers on the address share the socket.
+    pub fn has_socket_options(&self) -> bool {
+        self.reuseport || self.backlog.is_some() || self.ipv6only.is_some()
+    }
+}
+
+fn port(value: &str, port: &str) -> Result<u16, String> {
+    match port.parse() {
+        Ok(port) if port > 0 => Ok(port),
+        _ => Err(format!(
+            "invalid port in \"{}\" of the \"listen\" directive",
+            value
+        )),
+    }
+}
+
+/// Resolves the address part of a `listen` directive.
+fn addresses(value: &str) -> Result<Vec<SocketAddr>, String> {
+    resolve_addresses(value, &|host, port| {
+        (host, port).to_socket_addrs().map(Iterator::collect)
+    })
+}
+
+/// Like [`addresses`], with `resolve` looking up host names.
+fn resolve_addresses(
+    value: &str,
+    resolve: &dyn Fn(&str, u16) -> io::Result<Vec<SocketAddr>>,
+) -> Result<Vec<SocketAddr>, String> {
+    if value.starts_with("unix:") {
+        return Err("unix domain sockets are not supported".to_string());
+    }
+    if value.bytes().all(|b| b.is_ascii_digit()) {
+        let port = port(value, value)?;
+        return Ok(vec![SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port)]);
+    }
+    if let Some(rest) = value.strip_prefix('[') {
+        let end = rest
+            .find(']')
+            .ok_or_else(|| format!("invalid IPv6 address in \"{}\"", value))?;
+        let port = match &rest[end + 1..] {
+            "" => DEFAULT_PORT,
+            after => match after.strip_prefix(':') {
+                Some(after) => port(value, after)?,
+                None => return Err(format!("invalid host in \"{}\"", value)),
+            },
+        };
+        let ip = rest[..end]
+            .parse::<Ipv6Addr>()
+            .map_err(|_| format!("invalid IPv6 address in \"{}\"", value))?;
+        return Ok(vec![SocketAddr::new(ip.into(), port)]);
+    }
+    // `::1:80` could be `[::1]:80` or `[::]:1:80`, so as in nginx IPv6
+    // addresses need the brackets
+    if value.matches(':').count() > 1 {
+        return Err(format!(
+            "IPv6 address in \"{}\" must be in brackets, like \"[::1]:80\"",
+            value
+        ));
+    }
+    let (host, port) = match value.rsplit_once(':') {
+        Some((host, after)) => (host, port(value, after)?),
+        None => (value, DEFAULT_PORT),
+    };
+    if host == "*" {
+        return Ok(vec![SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port)]);
+    }
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+    let not_found = || {
+        format!(
+            "host not found in \"{}\" of the \"listen\" directive",
+            value
+        )
+    };
+    let mut resolved = Vec::new();
+    for address in resolve(host, port).map_err(|_| not_found())? {
+        if !resolved.contains(&address) {
+            resolved.push(address);
+        }
+    }
+    if resolved.is_empty() {
+        return Err(not_found());
+    }
+    Ok(resolved)
+}
+
+fn switch(option: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(format!("invalid {} flags \"{}\"", option, value)),
+    }
+}
+
+pub fn parse(directive: &Directive) -> Result<Listen, String> {
+    if directive.block.is_some() {
+        return Err("\"listen\" directive doesn't take a block".to_string());
+    }
+    let (address, options) = directive
+        .parameters
+        .split_first()
+        .ok_or("invalid number of arguments in \"listen\" directive")?;
+    let mut listen = Listen {
+        addresses: addresses(&address.value)?,
+        span: directive.span.clone(),
+        ..Listen::default()
+    };
+    for option in options {
+        let option = option.value.as_str();
+        match option {
+            // `default` is the old spelling
+            "default_server" | "default" => listen.default_server = true,
+            "ssl" => listen.ssl = true,
+            "http2" => listen.http2 = true,
+            "reuseport" => listen.reuseport = true,
+            _ => {
+                if let Some(backlog) = option.strip_prefix("backlog=") {
+                    match backlog.parse() {
+                        Ok(backlog) if backlog > 0 => listen.backlog = Some(backlog),
+                        _ => return Err(format!("invalid backlog \"{}\"", backlog)),
+                    }
+                } else if let Some(value) = option.strip_prefix("ipv6only=") {
+                    listen.ipv6only = Some(switch("ipv6only", value)?);
+                } else {
+                    return Err(format!("invalid parameter \"{}\"", option));
+                }
+            }
+        }
+    }
+    if listen.ipv6only.is_some() && listen.addresses.iter().any(SocketAddr::is_ipv4) {
+        return Err(format!(
+            "ipv6only can only be used with IPv6 addresses, not \"{}\"",
+            address.value
+        ));
+    }
+    Ok(listen)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listen(source: &str) -> Result<Listen, String> {
+        let block = parser::parse(source).unwrap();
+        parse(&block.directives[0])
+    }
+
+    fn addresses(source: &str) -> Vec<String> {
+        let listen = listen(&format!("listen {};", source)).unwrap();
+        listen.addresses.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_addresses() {
+        assert_eq!(addresses("8080"), ["0.0.0.0:8080"]);
+        assert_eq!(addresses("*:8080"), ["0.0.0.0:8080"]);
+        assert_eq!(addresses("127.0.0.1"), ["127.0.0.1:80"]);
+        assert_eq!(addresses("127.0.0.1:81"), ["127.0.0.1:81"]);
+        assert_eq!(addresses("[::]:80"), ["[::]:80"]);
+        assert_eq!(addresses("[::1]"), ["[::1]:80"]);
+    }
+
+    #[test]
+    fn resolves_host_names() {
+        // stands in for DNS, so the tests don't depend on it
+        let resolve = |value| {
+            super::resolve_addresses(value, &|host, port| match host {
+                "example.test" => Ok(vec![
+                    SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port),
+                    SocketAddr::new(Ipv6Addr::LOCALHOST.into(), port),
+                    SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port),
+                ]),
+                "empty.test" => Ok(Vec::new()),
+                _ => Err(io::Error::new(io::ErrorKind::NotFound, "unknown host")),
+            })
+        };
+        let addresses: Vec<String> = resolve("example.test:8080")
+            .unwrap()
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        assert_eq!(addresses, ["127.0.0.1:8080", "[::1]:8080"]);
+        for value in &["no-such-host.test:80", "empty.test"] {
+            assert_eq!(
+                resolve(value).unwrap_err(),
+                format!(
+                    "host not found in \"{}\" of the \"listen\" directive",
+                    value
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn parses_options() {
+        let listen =
+            listen("listen [::]:443 default_server ssl http2 reuseport backlog=1024 ipv6only=on;")
+                .unwrap();
+        assert!(listen.default_server && listen.ssl && listen.http2 && listen.reuseport);
+        assert_eq!(listen.backlog, Some(1024));
+        assert_eq!(listen.ipv6only, Some(true));
+        assert!(listen.has_socket_options());
+        assert_eq!(listen.span.start.column, 1);
+
+        let plain = self::listen("listen 80 default;").unwrap();
+        assert!(plain.default_server && !plain.ssl);
+        assert!(!plain.has_socket_options());
+    }
+
+    #[test]
+    fn rejects_invalid_listens() {
+        let error = |source| listen(source).unwrap_err();
+        assert_eq!(
+            error("listen;"),
+            "invalid number of arguments in \"listen\" directive"
+        );
+        assert_eq!(
+            error("listen 70000;"),
+            "invalid port in \"70000\" of the \"listen\" directive"
+        );
+        assert_eq!(
+            error("listen 127.0.0.1:0;"),
+            "invalid port in \"127.0.0.1:0\" of the \"listen\" directive"
+        );
+        assert_eq!(error("listen [::1;"), "invalid IPv6 address in \"[::1\"");
+        assert_eq!(error("listen [::1]x;"), "invalid host in \"[::1]x\"");
+        assert_eq!(error("listen 80 fast;"), "invalid parameter \"fast\"");
+        assert_eq!(error("listen 80 backlog=0;"), "invalid backlog \"0\"");
+        assert_eq!(
+            error("listen [::]:80 ipv6only=yes;"),
+            "invalid ipv6only flags \"yes\""
+        );
+        assert_eq!(
+            error("listen 80 ipv6only=on;"),
+            "ipv6only can only be used with IPv6 addresses, not \"80\""
+        );
+        assert_eq!(
+            error("listen unix:/tmp/paykan.sock;"),
+            "unix domain sockets are not supported"
+        );
+        assert_eq!(
+            error("listen ::1;"),
+            "IPv6 address in \"::1\" must be in brackets, like \"[::1]:80\""
+        );
+        assert_eq!(
+            error("listen ::1:8080;"),
+            "IPv6 address in \"::1:8080\" must be in brackets, like \"[::1]:80\""
+        );
+    }
+}
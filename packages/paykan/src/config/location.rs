@@ -0,0 +1,425 @@
+//! `location [=|^~|~|~*] uri { ... }` and finding the location of a request
+//! the way nginx does: an exact match wins right away, then the longest
+//! prefix is remembered and its nested locations are searched, then regexes
+//! are tried in config order unless the prefix had `^~`. The first matching
+//! regex wins, or the longest prefix when none matches.
+use super::{Files, Flag, LimitExcept};
+use crate::variables::Template;
+use parser::{
+    schema::{find_all, FromBlock, FromDirective, Problem},
+    Directive, Span,
+};
+use regex::Regex;
+use std::fmt;
+
+#[derive(Debug, Clone)]
+pub enum LocationPath {
+    /// `location = /uri`
+    Exact(String),
+    /// `location /uri` or, when `no_regex` is set, `location ^~ /uri`.
+    Prefix { prefix: String, no_regex: bool },
+    /// `location ~ regex`, or `location ~* regex` when `caseless` is set.
+    Regex { regex: Regex, caseless: bool },
+}
+
+impl LocationPath {
+    fn parse(directive: &Directive) -> Result<Self, String> {
+        let parameters: Vec<&str> = directive
+            .parameters
+            .iter()
+            .map(|p| p.value.as_str())
+            .collect();
+        let (modifier, uri) = match parameters[..] {
+            [modifier, uri] => (modifier, uri),
+            // the modifier can be written without a space for `=` and `~`
+            [uri] => match uri.strip_prefix('=') {
+                Some(exact) if !exact.is_empty() => ("=", exact),
+                _ => match uri.strip_prefix("~*").or_else(|| uri.strip_prefix('~')) {
+                    Some(regex) if !regex.is_empty() => (&uri[..uri.len() - regex.len()], regex),
+                    _ => ("", uri),
+                },
+            },
+            _ => return Err("invalid number of arguments in \"location\" directive".to_string()),
+        };
+        match modifier {
+            "=" => Ok(LocationPath::Exact(uri.to_string())),
+            "" | "^~" => Ok(LocationPath::Prefix {
+                prefix: uri.to_string(),
+                no_regex: modifier == "^~",
+            }),
+            "~" | "~*" => {
+                let caseless = modifier == "~*";
+                let pattern = if caseless {
+                    format!("(?i){}", uri)
+                } else {
+                    uri.to_string()
+                };
+                Regex::new(&pattern)
+                    .map(|regex| LocationPath::Regex { regex, caseless })
+                    .map_err(|e| format!("invalid regex \"{}\": {}", uri, e))
+            }
+            _ => Err(format!("invalid location modifier \"{}\"", modifier)),
+        }
+    }
+
+    /// The uri or regex as written in the config.
+    pub fn pattern(&self) -> &str {
+        match self {
+            LocationPath::Exact(uri) => uri,
+            LocationPath::Prefix { prefix, .. } => prefix,
+            LocationPath::Regex { regex, caseless } => {
+                &regex.as_str()[if *caseless { 4 } else { 0 }..]
+            }
+        }
+    }
+}
+
+impl fmt::Display for LocationPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let modifier = match self {
+            LocationPath::Exact(_) => "= ",
+            LocationPath::Prefix { no_regex: true, .. } => "^~ ",
+            LocationPath::Prefix { .. } => "",
+            LocationPath::Regex {
+                caseless: false, ..
+            } => "~ ",
+            LocationPath::Regex { caseless: true, .. } => "~* ",
+        };
+        write!(f, "{}{}", modifier, self.pattern())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Location {
+    /// The modifier and uri of the directive.
+    pub path: LocationPath,
+    pub span: Span,
+    pub locations: Vec<Location>,
+    pub root: Option<Template>,
+    pub alias: Option<Template>,
+    pub index: Option<Vec<String>>,
+    pub disable_symlinks: Option<Flag>,
+    /// Set here or inherited from the enclosing location.
+    pub limit_except: Option<LimitExcept>,
+    /// The file settings of this location, including inherited ones.
+    pub files: Files,
+}
+
+/// The directives of a location's block.
+#[derive(FromBlock)]
+#[from_block(name = "location")]
+struct LocationBlock {
+    #[directive(name = "location", repeated, from_directive)]
+    locations: Vec<Location>,
+    root: Option<Template>,
+    alias: Option<Template>,
+    #[directive(args = "1..")]
+    index: Option<Vec<String>>,
+    disable_symlinks: Option<Flag>,
+    #[directive(from_directive)]
+    limit_except: Option<LimitExcept>,
+}
+
+impl FromDirective for Location {
+    fn from_directive(directive: &Directive, problems: &mut Vec<Problem>) -> Option<Self> {
+        let path = LocationPath::parse(directive)
+            .map_err(|message| problems.push(Problem::new(message, &directive.span)))
+            .ok();
+        let block = match &directive.block {
+            Some(block) => block,
+            None => {
+                problems.push(Problem::new(
+                    "\"location\" directive requires a block",
+                    &directive.span,
+                ));
+                return None;
+            }
+        };
+        let body = LocationBlock::from_block(block, problems);
+        let mut valid = true;
+        if let (Some(path), Some(body)) = (&path, &body) {
+            if body.alias.is_some() {
+                let message = match path {
+                    _ if body.root.is_some() => Some(
+                        "\"alias\" directive is duplicate, \"root\" directive was specified earlier",
+                    ),
+                    LocationPath::Regex { .. } => {
+                        Some("\"alias\" can't be used in a regex location")
+                    }
+                    _ => None,
+                };
+                if let Some(message) = message {
+                    problems.push(Problem::new(message, &directive.span));
+                    valid = false;
+                }
+            }
+        }
+        // a mistake anywhere in the block drops its nested locations, they are
+        // read again to check them too; their own problems were reported above
+        let nested: Vec<Location>;
+        let locations = match &body {
+            Some(body) => &body.locations,
+            None => {
+                nested = find_all(block, "location")
+                    .filter_map(|directive| Location::from_directive(directive, &mut Vec::new()))
+                    .collect();
+                &nested
+            }
+        };
+        validate(path.as_ref(), locations, problems);
+        let body = body.filter(|_| valid)?;
+        Some(Location {
+            path: path?,
+            span: directive.span.clone(),
+            locations: body.locations,
+            root: body.root,
+            alias: body.alias,
+            index: body.index,
+            disable_symlinks: body.disable_symlinks,
+            limit_except: body.limit_except,
+            files: Files::default(),
+        })
+    }
+}
+
+/// Reports locations of the same block with the same uri, and nested
+/// locations that can never match because they are outside their parent.
+pub fn validate(
+    parent: Option<&LocationPath>,
+    locations: &[Location],
+    problems: &mut Vec<Problem>,
+) {
+    for (index, location) in locations.iter().enumerate() {
+        let path = &location.path;
+        if let Some(parent) = parent {
+            let outside = match (parent, path) {
+                (LocationPath::Exact(_), _) => Some("cannot be inside the exact location"),
+                (_, LocationPath::Regex { .. }) => None,
+                (parent, path) if !path.pattern().starts_with(parent.pattern()) => {
+                    Some("is outside location")
+                }
+                _ => None,
+            };
+            if let Some(message) = outside {
+                problems.push(Problem::new(
+                    format!(
+                        "location \"{}\" {} \"{}\"",
+                        path.pattern(),
+                        message,
+                        parent.pattern()
+                    ),
+                    &location.span,
+                ));
+            }
+        }
+        let duplicate = locations[..index]
+            .iter()
+            .any(|other| match (&other.path, path) {
+                (LocationPath::Exact(a), LocationPath::Exact(b)) => a == b,
+                (
+                    LocationPath::Prefix { prefix: a, .. },
+                    LocationPath::Prefix { prefix: b, .. },
+                ) => a == b,
+                _ => false,
+            });
+        if duplicate {
+            problems.push(Problem::new(
+                format!("duplicate location \"{}\"", path.pattern()),
+                &location.span,
+            ));
+        }
+    }
+}
+
+enum Found<'a> {
+    /// An exact or regex match, nothing else is looked at.
+    Final(&'a Location),
+    /// The longest prefix, if any. Regexes of the outer blocks are still tried.
+    Prefix(Option<&'a Location>),
+}
+
+fn find_in<'a>(locations: &'a [Location], uri: &str) -> Found<'a> {
+    let mut longest: Option<(&Location, usize, bool)> = None;
+    for location in locations {
+        match &location.path {
+            LocationPath::Exact(exact) if exact == uri => return Found::Final(location),
+            LocationPath::Prefix { prefix, no_regex }
+                if uri.starts_with(prefix.as_str())
+                    && longest.is_none_or(|(_, len, _)| prefix.len() > len) =>
+            {
+                longest = Some((location, prefix.len(), *no_regex));
+            }
+            _ => {}
+        }
+    }
+    let mut found = longest.map(|(location, _, _)| location);
+    if let Some((location, _, no_regex)) = longest {
+        match find_in(&location.locations, uri) {
+            Found::Final(nested) => return Found::Final(nested),
+            Found::Prefix(Some(nested)) => found = Some(nested),
+            Found::Prefix(None) => {}
+        }
+        if no_regex {
+            return Found::Prefix(found);
+        }
+    }
+    for location in locations {
+        if let LocationPath::Regex { regex, .. } = &location.path {
+            if regex.is_match(uri) {
+                return match find_in(&location.locations, uri) {
+                    Found::Final(nested) | Found::Prefix(Some(nested)) => Found::Final(nested),
+                    Found::Prefix(None) => Found::Final(location),
+                };
+            }
+        }
+    }
+    Found::Prefix(found)
+}
+
+/// The location for the path of a request, without its query string.
+pub fn find<'a>(locations: &'a [Location], uri: &str) -> Option<&'a Location> {
+    match find_in(locations, uri) {
+        Found::Final(location) => Some(location),
+        Found::Prefix(location) => location,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(FromBlock)]
+    struct Locations {
+        #[directive(name = "location", repeated, from_directive)]
+        locations: Vec<Location>,
+    }
+
+    fn locations(source: &str) -> Result<Vec<Location>, Vec<String>> {
+        let mut problems = Vec::new();
+        let block = parser::parse(source).unwrap();
+        Locations::from_block(&block, &mut problems)
+            .map(|l| {
+                validate(None, &l.locations, &mut problems);
+                l.locations
+            })
+            .filter(|_| problems.is_empty())
+            .ok_or_else(|| {
+                // only the first line of regex errors
+                let first_line = |p: Problem| p.message.lines().next().unwrap().to_string();
+                problems.into_iter().map(first_line).collect()
+            })
+    }
+
+    const CONFIG: &str = r#"
+        location = / { }
+        location / { }
+        location /documents/ {
+            location ~ \.pdf$ { }
+            location /documents/archive/ { }
+        }
+        location ^~ /images/ {
+            location ~ \.gif$ { }
+        }
+        location ~* \.(gif|jpg|jpeg)$ { }
+        location ~ ^/api/v\d+ {
+            location ~ /users$ { }
+        }
+        location /api/ { }
+        location =/exact { }
+        location ~^/Case { }
+        location /static/ {
+            location ^~ /static/raw/ { }
+        }
+        location ~ /raw/ { }
+    "#;
+
+    #[test]
+    fn follows_nginx_order() {
+        let locations = locations(CONFIG).unwrap();
+        let cases = [
+            ("/", "= /"),
+            ("/index.html", "/"),
+            // the longest prefix wins regardless of order
+            ("/documents/a.txt", "/documents/"),
+            ("/documents/archive/a.txt", "/documents/archive/"),
+            // nested regexes are tried before the outer ones
+            ("/documents/a.pdf", "~ \\.pdf$"),
+            ("/documents/archive/a.pdf", "~ \\.pdf$"),
+            // regexes win over plain prefixes
+            ("/documents/a.jpg", "~* \\.(gif|jpg|jpeg)$"),
+            ("/a.JPG", "~* \\.(gif|jpg|jpeg)$"),
+            // `^~` skips the regexes of its block, but not nested ones
+            ("/images/a.jpg", "^~ /images/"),
+            ("/images/a.gif", "~ \\.gif$"),
+            // the first regex in config order wins
+            ("/api/v2/a.gif", "~* \\.(gif|jpg|jpeg)$"),
+            ("/api/v2/users", "~ /users$"),
+            ("/api/v2/items", "~ ^/api/v\\d+"),
+            ("/api/items", "/api/"),
+            ("/exact", "= /exact"),
+            ("/exact/", "/"),
+            ("/case", "/"),
+            ("/Case", "~ ^/Case"),
+            // a nested `^~` doesn't stop the regexes of the outer block
+            ("/static/raw/a", "~ /raw/"),
+            ("/static/a", "/static/"),
+        ];
+        for (uri, expected) in &cases {
+            let found = find(&locations, uri).map(|l| l.path.to_string());
+            assert_eq!(found.as_deref(), Some(*expected), "{}", uri);
+        }
+        assert!(find(&locations[2..3], "/other").is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_locations() {
+        assert_eq!(
+            locations(
+                "location /a { location /b { } }\n\
+                 location = /c { location /c/d { } }\n\
+                 location /a { }\n\
+                 location ^~ /a { }\n\
+                 location ~ ( { }\n\
+                 location >= /x { }\n\
+                 location /x;\n\
+                 location { }"
+            )
+            .unwrap_err(),
+            [
+                "location \"/b\" is outside location \"/a\"",
+                "location \"/c/d\" cannot be inside the exact location \"/c\"",
+                "invalid regex \"(\": regex parse error:",
+                "invalid location modifier \">=\"",
+                "\"location\" directive requires a block",
+                "invalid number of arguments in \"location\" directive",
+            ]
+        );
+        assert_eq!(
+            locations("location /a { }\nlocation ^~ /a { }\nlocation = /a { }").unwrap_err(),
+            ["duplicate location \"/a\""]
+        );
+        assert_eq!(
+            locations("location /a { root /r; alias /b; }\nlocation ~ ^/c { alias /c; }")
+                .unwrap_err(),
+            [
+                "\"alias\" directive is duplicate, \"root\" directive was specified earlier",
+                "\"alias\" can't be used in a regex location",
+            ]
+        );
+        // a mistake in the block or the uri doesn't hide the nested ones
+        assert_eq!(
+            locations(
+                "location /a { root; location /b { } location /a/c { } location /a/c { } }\n\
+                 location >= /x { location /y { } location /y { } }"
+            )
+            .unwrap_err(),
+            [
+                "\"root\" directive takes exactly one argument",
+                "location \"/b\" is outside location \"/a\"",
+                "duplicate location \"/a/c\"",
+                "invalid location modifier \">=\"",
+                "duplicate location \"/y\"",
+            ]
+        );
+    }
+}
@@ -0,0 +1,244 @@
+//! `server_name` and picking the server for a request's `Host`, in the order
+//! nginx uses: the exact name, the longest name starting with `*`, the
+//! longest name ending with `*`, the first regex in config order and at last
+//! the default server of the address.
+use super::Server;
+use regex::Regex;
+use std::{fmt, str::FromStr};
+
+#[derive(Debug, Clone)]
+pub enum ServerName {
+    Exact(String),
+    /// `*.example.com`, or `.example.com` which also matches `example.com`.
+    Suffix {
+        suffix: String,
+        domain: bool,
+    },
+    /// `www.example.*`, kept as `www.example.`.
+    Prefix(String),
+    /// `~^api\d+\.`, matched case-insensitively.
+    Regex(Regex),
+}
+
+impl FromStr for ServerName {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if let Some(pattern) = value.strip_prefix('~') {
+            return Regex::new(&format!("(?i){}", pattern))
+                .map(ServerName::Regex)
+                .map_err(|e| format!("invalid regex \"{}\": {}", pattern, e));
+        }
+        let invalid = || format!("invalid server name or wildcard \"{}\"", value);
+        let name = value.to_ascii_lowercase();
+        let name = if let Some(suffix) = name.strip_prefix('*') {
+            if !suffix.starts_with('.') || suffix.len() < 2 {
+                return Err(invalid());
+            }
+            ServerName::Suffix {
+                suffix: suffix.to_string(),
+                domain: false,
+            }
+        } else if let Some(prefix) = name.strip_suffix('*') {
+            if !prefix.ends_with('.') || prefix.len() < 2 {
+                return Err(invalid());
+            }
+            ServerName::Prefix(prefix.to_string())
+        } else if name.starts_with('.') && name.len() > 1 {
+            ServerName::Suffix {
+                suffix: name,
+                domain: true,
+            }
+        } else {
+            ServerName::Exact(name)
+        };
+        match &name {
+            ServerName::Exact(text)
+            | ServerName::Suffix { suffix: text, .. }
+            | ServerName::Prefix(text)
+                if text.contains('*') =>
+            {
+                Err(invalid())
+            }
+            _ => Ok(name),
+        }
+    }
+}
+
+impl fmt::Display for ServerName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerName::Exact(name) => f.write_str(name),
+            ServerName::Suffix {
+                suffix,
+                domain: true,
+            } => f.write_str(suffix),
+            ServerName::Suffix { suffix, .. } => write!(f, "*{}", suffix),
+            ServerName::Prefix(prefix) => write!(f, "{}*", prefix),
+            ServerName::Regex(regex) => write!(f, "~{}", &regex.as_str()[4..]),
+        }
+    }
+}
+
+impl ServerName {
+    /// How well the name matches `host`, `None` if it doesn't. Within a kind
+    /// longer wildcards rank higher.
+    fn rank(&self, host: &str) -> Option<(u8, usize)> {
+        match self {
+            ServerName::Exact(name) if name == host => Some((3, 0)),
+            ServerName::Suffix { suffix, domain } => {
+                let matches = (host.len() > suffix.len() && host.ends_with(&suffix[..]))
+                    || (*domain && host == &suffix[1..]);
+                Some((2, suffix.len())).filter(|_| matches)
+            }
+            ServerName::Prefix(prefix)
+                if host.len() > prefix.len() && host.starts_with(&prefix[..]) =>
+            {
+                Some((1, prefix.len()))
+            }
+            ServerName::Regex(regex) if regex.is_match(host) => Some((0, 0)),
+            _ => None,
+        }
+    }
+}
+
+/// The host of a `Host` header as it's compared with server names: lower
+/// case, without the port and the trailing dot. `None` when nginx would
+/// reject it, i.e. it has a `/`, `\`, NUL or `..`, which could reach out of
+/// a root built with `$host`.
+pub fn normalize_host(host: &str) -> Option<String> {
+    let host = host.trim();
+    let host = match host.rfind(':') {
+        Some(index) if !host.ends_with(']') => &host[..index],
+        _ => host,
+    };
+    if host.contains(&['/', '\\', '\0'][..]) || host.contains("..") {
+        return None;
+    }
+    Some(host.strip_suffix('.').unwrap_or(host).to_ascii_lowercase())
+}
+
+/// The index of the server in `candidates` whose name matches `host` best,
+/// or `None` when no name matches. Ties go to the server defined first.
+pub fn find(servers: &[Server], candidates: &[usize], host: &str) -> Option<usize> {
+    let mut best: Option<((u8, usize), usize)> = None;
+    for &index in candidates {
+        for name in &servers[index].server_name {
+            if let Some(rank) = name.rank(host) {
+                // regexes are tried in config order, so the first one wins
+                if best.is_none_or(|(best, _)| rank > best) {
+                    best = Some((rank, index));
+                }
+            }
+        }
+    }
+    best.map(|(_, index)| index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(names: &[&str]) -> Server {
+        Server {
+            server_name: names.iter().map(|name| name.parse().unwrap()).collect(),
+            ..Server::default()
+        }
+    }
+
+    #[test]
+    fn parses_names() {
+        let names = [
+            "Example.com",
+            "*.example.com",
+            ".example.org",
+            "www.example.*",
+            "~^api\\d+\\.",
+            "",
+        ];
+        let parsed: Vec<_> = names
+            .iter()
+            .map(|name| name.parse::<ServerName>().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            parsed,
+            [
+                "example.com",
+                "*.example.com",
+                ".example.org",
+                "www.example.*",
+                "~^api\\d+\\.",
+                ""
+            ]
+        );
+        for invalid in &["*", "*example.com", "www.*.com", "example*", "*.a.*"] {
+            assert_eq!(
+                invalid.parse::<ServerName>().unwrap_err(),
+                format!("invalid server name or wildcard \"{}\"", invalid)
+            );
+        }
+        assert!("~("
+            .parse::<ServerName>()
+            .unwrap_err()
+            .starts_with("invalid regex \"(\""));
+    }
+
+    #[test]
+    fn normalizes_hosts() {
+        let normalize = |host| normalize_host(host).unwrap();
+        assert_eq!(normalize("Example.COM:8080"), "example.com");
+        assert_eq!(normalize("example.com."), "example.com");
+        assert_eq!(normalize("[::1]:80"), "[::1]");
+        assert_eq!(normalize("[::1]"), "[::1]");
+        assert_eq!(normalize(""), "");
+        for host in &[
+            "..",
+            "a..b",
+            "a/b",
+            "..:80",
+            "a\\b",
+            "a\0b",
+            "example.com..",
+            "./",
+        ] {
+            assert_eq!(normalize_host(host), None, "{}", host);
+        }
+    }
+
+    #[test]
+    fn follows_nginx_precedence() {
+        let servers = [
+            server(&["~^www\\..+\\.com$"]),
+            server(&["www.*"]),
+            server(&["www.example.*"]),
+            server(&["*.com"]),
+            server(&["*.example.com"]),
+            server(&["www.example.com", "example.net"]),
+            server(&["~^api\\d+\\."]),
+            server(&[".example.org"]),
+            server(&["~^API"]),
+            server(&[""]),
+        ];
+        let all: Vec<_> = (0..servers.len()).collect();
+        let cases = [
+            ("www.example.com", Some(5)),
+            ("example.net", Some(5)),
+            ("mail.example.com", Some(4)),
+            ("www.other.com", Some(3)),
+            ("www.example.net", Some(2)),
+            ("www.other.net", Some(1)),
+            ("api1.internal", Some(6)),
+            ("api.internal", Some(8)),
+            ("example.org", Some(7)),
+            ("a.b.example.org", Some(7)),
+            ("", Some(9)),
+            ("unknown.net", None),
+        ];
+        for (host, expected) in &cases {
+            assert_eq!(find(&servers, &all, host), *expected, "{}", host);
+        }
+        // only the servers of the listener are considered
+        assert_eq!(find(&servers, &[0, 1], "www.example.com"), Some(1));
+        assert_eq!(find(&servers, &[0], "www.example.com"), Some(0));
+    }
+}
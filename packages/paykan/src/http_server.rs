@@ -1,16 +1,741 @@
+//! HTTP1.1 based on https://datatracker.ietf.org/doc/html/rfc2616
 use std::error::Error;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
-///! HTTP1.1 based on https://datatracker.ietf.org/doc/html/rfc2616
-use crate::config::Server;
-use crate::lazy_stream_reader::HttpLazyStreamReader;
-use tokio::net::TcpListener;
+use crate::config::{server_name, Listener, Server};
+use crate::lazy_stream_reader::{HttpLazyStreamReader, HttpMethod, HttpParseError, HttpVersion};
+use crate::response_writer::{self, Body, HttpResponseWriter, StatusCode};
+use crate::static_files::{self, Outcome};
+use crate::variables::RequestContext;
+use socket2::{Domain, Socket, Type};
+use tokio::fs;
+use tokio::io::AsyncWrite;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{watch, Semaphore};
 
-pub async fn serve(server: &Server) -> Result<(), Box<dyn Error>> {
-    println!("starting {}", server.listen);
-    let listener = TcpListener::bind(server.listen).await?;
+/// The backlog used when `listen` doesn't set one, as in nginx.
+const DEFAULT_BACKLOG: i32 = 511;
+/// How long the head of a request may take to arrive, and a new connection
+/// may wait for its first request, nginx's default `client_header_timeout`.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long sending a response may take, so a client that doesn't read
+/// can't hold up a shutdown. Longer than nginx's `send_timeout`, which is
+/// for each write.
+const SEND_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long to wait after accepting a connection failed.
+const ACCEPT_RETRY: Duration = Duration::from_millis(100);
+
+/// Opens the socket of `listener` with the options of its `listen` directive.
+/// IPv6 sockets only accept IPv6 unless `ipv6only=off` is set, so `[::]:80`
+/// and `0.0.0.0:80` can both be used.
+pub fn bind(listener: &Listener) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(listener.address), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    if listener.reuseport {
+        socket.set_reuse_port(true)?;
+    }
+    if listener.address.is_ipv6() {
+        socket.set_only_v6(listener.ipv6only.unwrap_or(true))?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&listener.address.into())?;
+    let backlog = listener.backlog.map_or(DEFAULT_BACKLOG, |backlog| {
+        backlog.min(i32::MAX as u32) as i32
+    });
+    socket.listen(backlog)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Groups the listeners by the socket that accepts their connections. A
+/// wildcard address like `0.0.0.0:80` also accepts the connections for the
+/// other addresses of its port and family, so those aren't bound at all.
+/// The listener to bind comes first in each group.
+pub fn sockets(listeners: &[Listener]) -> Vec<Vec<&Listener>> {
+    let wildcard = |address: &SocketAddr| {
+        listeners.iter().find(|l| {
+            l.address.ip().is_unspecified()
+                && l.address.port() == address.port()
+                && l.address.is_ipv4() == address.is_ipv4()
+        })
+    };
+    let mut sockets: Vec<Vec<&Listener>> = Vec::new();
+    for listener in listeners {
+        let bound = wildcard(&listener.address).unwrap_or(listener);
+        match sockets
+            .iter_mut()
+            .find(|group| group[0].address == bound.address)
+        {
+            Some(group) if bound.address != listener.address => group.push(listener),
+            Some(_) => {}
+            None if bound.address == listener.address => sockets.push(vec![listener]),
+            None => sockets.push(vec![bound, listener]),
+        }
+    }
+    sockets
+}
+
+/// A small nginx-like page for an error or redirect status.
+fn status_page(status: StatusCode) -> String {
+    format!(
+        "<html>\r\n<head><title>{0}</title></head>\r\n<body>\r\n<center><h1>{0}</h1></center>\r\n<hr><center>paykan</center>\r\n</body>\r\n</html>\r\n",
+        status
+    )
+}
+
+async fn send_status<W: AsyncWrite + Unpin>(
+    response: &mut HttpResponseWriter<W>,
+    status: StatusCode,
+) -> io::Result<()> {
+    response.status(status).header("Content-Type", "text/html");
+    response.send(status_page(status).as_bytes()).await
+}
+
+/// Writes the response for `outcome`.
+async fn respond<W: AsyncWrite + Unpin>(
+    response: &mut HttpResponseWriter<W>,
+    outcome: Outcome,
+) -> io::Result<()> {
+    match outcome {
+        Outcome::File {
+            path,
+            len,
+            modified,
+        } => match fs::File::open(&path).await {
+            Ok(mut file) => {
+                response.header("Content-Type", static_files::content_type(&path));
+                if let Some(modified) = modified {
+                    response.header("Last-Modified", response_writer::http_date(modified));
+                }
+                response.send_head(Body::Length(len)).await?;
+                response.copy(&mut file).await?;
+                response.finish().await
+            }
+            Err(error) => send_status(response, static_files::status(&error)).await,
+        },
+        Outcome::Redirect(target) => {
+            response.header("Location", target);
+            send_status(response, StatusCode::MOVED_PERMANENTLY).await
+        }
+        Outcome::Error(status) => send_status(response, status).await,
+    }
+}
+
+/// The status a request that couldn't be read is answered with, `None` when
+/// the client is gone.
+fn error_status(error: &HttpParseError) -> Option<StatusCode> {
+    match error {
+        HttpParseError::BadMethod(_)
+        | HttpParseError::BadUri
+        | HttpParseError::BadVersion(_)
+        | HttpParseError::MalformedHeader(_)
+        | HttpParseError::BadLineEnding => Some(StatusCode::BAD_REQUEST),
+        HttpParseError::UriTooLong => Some(StatusCode::URI_TOO_LONG),
+        HttpParseError::HeadersTooLarge => Some(StatusCode::HEADER_FIELDS_TOO_LARGE),
+        HttpParseError::UnsupportedVersion(_) => Some(StatusCode::HTTP_VERSION_NOT_SUPPORTED),
+        HttpParseError::UnexpectedEof | HttpParseError::Io(_) => None,
+    }
+}
+
+/// Picks the server and location for the request and finds its file.
+async fn route<'a>(
+    reader: &HttpLazyStreamReader,
+    listener: &Listener,
+    servers: &'a [Server],
+    local_addr: SocketAddr,
+    remote_addr: SocketAddr,
+) -> Result<(&'a Server, Outcome), HttpParseError> {
+    let host = reader.header("Host").await?;
+    let server = &servers[listener.server_for(servers, host.as_deref())];
+    // as in nginx, an invalid host is answered with 400 by the default server,
+    // and HTTP/1.1 requires one
+    let valid_host = match host.as_deref() {
+        Some(host) => server_name::normalize_host(host).is_some(),
+        None => *reader.version().await? != HttpVersion::Http1_1,
+    };
+    let resource = reader.resource().await?.clone();
+    let (path, query) = match resource.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (&resource[..], None),
+    };
+    let method = reader.method().await?.clone();
+    // a body framed ambiguously could hide a second request
+    reader.body().await?;
+    let outcome = match static_files::normalize_uri(path).filter(|_| valid_host) {
+        None => Outcome::Error(StatusCode::BAD_REQUEST),
+        Some(uri) => {
+            let location = server.location(&uri);
+            let limit = location.and_then(|location| location.limit_except.as_ref());
+            if limit.is_some_and(|limit| !limit.permits(&method, remote_addr.ip())) {
+                Outcome::Error(StatusCode::FORBIDDEN)
+            } else if method != HttpMethod::Get && method != HttpMethod::Head {
+                Outcome::Error(StatusCode::METHOD_NOT_ALLOWED)
+            } else {
+                let files = location.map_or(&server.files, |l| &l.files);
+                let context = RequestContext {
+                    reader,
+                    server,
+                    local_addr,
+                    remote_addr,
+                };
+                static_files::resolve(files, location, &uri, query, &context).await
+            }
+        }
+    };
+    Ok((server, outcome))
+}
+
+/// Reads the requests on `stream` and answers each with a file. The
+/// connection is kept open between them unless the client, the server's
+/// `keepalive_timeout` and `keepalive_requests` or a shutdown say otherwise.
+async fn handle(
+    stream: TcpStream,
+    listener: &Listener,
+    servers: &[Server],
+    connections: &Connections,
+) -> Result<(), Box<dyn Error>> {
+    let (local_addr, remote_addr) = (stream.local_addr()?, stream.peer_addr()?);
+    let (read, mut write) = stream.into_split();
+    let mut reader = HttpLazyStreamReader::new(Box::pin(read));
+    // how long to wait for the next request
+    let mut idle = HEADER_TIMEOUT;
+    let mut requests = 0;
+    loop {
+        requests += 1;
+        let started = tokio::select! {
+            // a request that already arrived is still answered
+            biased;
+            started = tokio::time::timeout(idle, reader.started()) => {
+                started.unwrap_or(Ok(false))?
+            }
+            _ = connections.closing() => false,
+        };
+        if !started {
+            return Ok(());
+        }
+        // errors in the head are answered by `route`
+        if tokio::time::timeout(HEADER_TIMEOUT, reader.headers())
+            .await
+            .is_err()
+        {
+            return Ok(());
+        }
+        match route(&reader, listener, servers, local_addr, remote_addr).await {
+            Ok((server, outcome)) => {
+                let keepalive = server.keepalive;
+                let keep_alive = reader.keep_alive().await?
+                    && !keepalive.timeout.is_zero()
+                    && requests < keepalive.requests
+                    && !connections.is_closing();
+                let version = *reader.version().await?;
+                let mut response =
+                    HttpResponseWriter::new(&mut write, version, reader.method().await?);
+                response.set_keep_alive(keep_alive);
+                match tokio::time::timeout(SEND_TIMEOUT, respond(&mut response, outcome)).await {
+                    Ok(result) => result?,
+                    // the client stopped reading
+                    Err(_) => return Ok(()),
+                }
+                // the access log: client, server, request line and status
+                let name = server.name();
+                println!(
+                    "{} {} \"{} {} {}\" {}",
+                    remote_addr.ip(),
+                    if name.is_empty() { "-" } else { &name },
+                    reader.method().await?,
+                    reader.resource().await?,
+                    version.as_str(),
+                    response.status_code().0
+                );
+                if !response.keep_alive() {
+                    return Ok(());
+                }
+                idle = keepalive.timeout;
+            }
+            Err(error) => {
+                let status = error_status(&error).ok_or_else(|| error.clone())?;
+                println!("{}: {}", remote_addr, error);
+                // GET and HTTP/1.1 unless the request line was read before
+                let method = reader.method().await.map_or(HttpMethod::Get, Clone::clone);
+                let version = reader.version().await.map_or(HttpVersion::Http1_1, |v| *v);
+                let mut response = HttpResponseWriter::new(&mut write, version, &method);
+                // the stream is out of step with the requests
+                response.set_keep_alive(false);
+                // closed either way, a client that doesn't read is left
+                let sent = tokio::time::timeout(SEND_TIMEOUT, send_status(&mut response, status));
+                sent.await.unwrap_or(Ok(()))?;
+                return Ok(());
+            }
+        }
+        // the rest of the body gets as long as the next request would
+        let next = tokio::select! {
+            biased;
+            next = tokio::time::timeout(idle, reader.next_request()) => {
+                next.unwrap_or(Ok(None))?
+            }
+            _ = connections.closing() => None,
+        };
+        reader = match next {
+            Some(next) => next,
+            None => return Ok(()),
+        };
+    }
+}
+
+/// The connections of every listener: at most `worker_connections` are open
+/// at once, and on shutdown no more are accepted while the open ones finish.
+pub struct Connections {
+    limit: u32,
+    permits: Arc<Semaphore>,
+    active: AtomicUsize,
+    accepted: AtomicU64,
+    handled: AtomicU64,
+    shutdown: watch::Sender<bool>,
+    // kept so the value sent on shutdown is there for later receivers too
+    closing: watch::Receiver<bool>,
+}
+
+impl Connections {
+    pub fn new(worker_connections: u32) -> Self {
+        let (shutdown, closing) = watch::channel(false);
+        Self {
+            limit: worker_connections,
+            permits: Arc::new(Semaphore::new(worker_connections as usize)),
+            active: AtomicUsize::new(0),
+            accepted: AtomicU64::new(0),
+            handled: AtomicU64::new(0),
+            shutdown,
+            closing,
+        }
+    }
+
+    /// The connections open right now.
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// The connections accepted since the start.
+    pub fn accepted(&self) -> u64 {
+        self.accepted.load(Ordering::Relaxed)
+    }
+
+    /// The connections that were accepted and have been closed since.
+    pub fn handled(&self) -> u64 {
+        self.handled.load(Ordering::Relaxed)
+    }
+
+    /// Stops accepting connections, the open ones go on.
+    pub fn shutdown(&self) {
+        let _ = self.shutdown.send(true);
+    }
+
+    /// Whether [`shutdown`](Self::shutdown) was called.
+    pub fn is_closing(&self) -> bool {
+        *self.closing.borrow()
+    }
+
+    /// Resolves once [`shutdown`](Self::shutdown) was called.
+    pub async fn closing(&self) {
+        let mut closing = self.closing.clone();
+        while !*closing.borrow() {
+            if closing.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Resolves once every connection is closed.
+    pub async fn drained(&self) {
+        // each open connection holds a permit until it's closed
+        let _ = self.permits.acquire_many(self.limit).await;
+    }
+}
+
+/// Accepts connections on the socket of `listeners[0]` and picks the server
+/// for each by the address it arrived on and its `Host` header. Every
+/// connection is handled by a task of its own, so a slow client doesn't hold
+/// up the others. Returns once `connections` is shut down.
+pub async fn serve(
+    listeners: Vec<&'static Listener>,
+    servers: &'static [Server],
+    connections: Arc<Connections>,
+) -> Result<(), Box<dyn Error>> {
+    println!("starting {}", listeners[0].address);
+    let tcp_listener = bind(listeners[0])?;
+    accept(tcp_listener, listeners, servers, connections).await
+}
+
+async fn accept(
+    tcp_listener: TcpListener,
+    listeners: Vec<&'static Listener>,
+    servers: &'static [Server],
+    connections: Arc<Connections>,
+) -> Result<(), Box<dyn Error>> {
     loop {
-        let (stream, _) = listener.accept().await?;
-        let reader = HttpLazyStreamReader::new(Box::pin(stream));
-        println!("method: {:?}", reader.method().await);
+        // past `worker_connections` new connections wait in the backlog
+        let permit = tokio::select! {
+            permit = connections.permits.clone().acquire_owned() => permit?,
+            _ = connections.closing() => return Ok(()),
+        };
+        let accepted = tokio::select! {
+            accepted = tcp_listener.accept() => accepted,
+            _ = connections.closing() => return Ok(()),
+        };
+        let accepted = accepted.and_then(|(stream, _)| Ok((stream.local_addr()?, stream)));
+        // a failed connection doesn't stop the listener
+        let (local_addr, stream) = match accepted {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                eprintln!("{}: accept failed: {}", listeners[0].address, error);
+                // e.g. out of file descriptors, which won't be better at once
+                tokio::time::sleep(ACCEPT_RETRY).await;
+                continue;
+            }
+        };
+        connections.accepted.fetch_add(1, Ordering::Relaxed);
+        connections.active.fetch_add(1, Ordering::Relaxed);
+        let listener = *listeners
+            .iter()
+            .find(|l| l.address == local_addr)
+            .unwrap_or(&listeners[0]);
+        let connections = connections.clone();
+        tokio::spawn(async move {
+            if let Err(error) = handle(stream, listener, servers, &connections).await {
+                eprintln!("{}: {}", local_addr, error);
+            }
+            connections.active.fetch_sub(1, Ordering::Relaxed);
+            connections.handled.fetch_add(1, Ordering::Relaxed);
+            drop(permit);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::KeepAlive;
+    use std::time::Instant;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::task::JoinHandle;
+    use tokio::time::{sleep, timeout};
+
+    fn listener(address: &str) -> Listener {
+        Listener {
+            address: address.parse().unwrap(),
+            servers: vec![0],
+            default_server: 0,
+            reuseport: false,
+            backlog: None,
+            ipv6only: None,
+        }
+    }
+
+    #[test]
+    fn binds_each_port_once() {
+        let listeners = [
+            listener("127.0.0.1:8080"),
+            listener("0.0.0.0:8080"),
+            listener("10.0.0.1:8080"),
+            listener("127.0.0.1:8081"),
+            listener("[::1]:8080"),
+            listener("[::]:8080"),
+        ];
+        let sockets: Vec<Vec<String>> = sockets(&listeners)
+            .iter()
+            .map(|group| group.iter().map(|l| l.address.to_string()).collect())
+            .collect();
+        assert_eq!(
+            sockets,
+            [
+                vec!["0.0.0.0:8080", "127.0.0.1:8080", "10.0.0.1:8080"],
+                vec!["127.0.0.1:8081"],
+                vec!["[::]:8080", "[::1]:8080"],
+            ]
+        );
+    }
+
+    async fn wait_for(check: impl Fn() -> bool) {
+        while !check() {
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Accepts connections for `server` on a free port.
+    fn start(
+        server: Server,
+        worker_connections: u32,
+    ) -> (SocketAddr, Arc<Connections>, JoinHandle<Result<(), String>>) {
+        let listener: &'static Listener = Box::leak(Box::new(listener("127.0.0.1:0")));
+        let servers: &'static [Server] = Box::leak(Box::new([server]));
+        let tcp_listener = bind(listener).unwrap();
+        let address = tcp_listener.local_addr().unwrap();
+        let connections = Arc::new(Connections::new(worker_connections));
+        let accepting = tokio::spawn({
+            let connections = connections.clone();
+            async move {
+                accept(tcp_listener, vec![listener], servers, connections)
+                    .await
+                    .map_err(|error| error.to_string())
+            }
+        });
+        (address, connections, accepting)
+    }
+
+    /// Everything sent until the connection is closed.
+    async fn read_all(mut stream: TcpStream) -> String {
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    /// One response with a status page.
+    async fn read_page(stream: &mut TcpStream) -> String {
+        let mut response = Vec::new();
+        while !response.ends_with(b"</html>\r\n") {
+            let mut buffer = [0; 1024];
+            let n = stream.read(&mut buffer).await.unwrap();
+            assert_ne!(
+                n,
+                0,
+                "closed after {:?}",
+                String::from_utf8_lossy(&response)
+            );
+            response.extend_from_slice(&buffer[..n]);
+        }
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_hosts() {
+        let (address, _, _) = start(Server::default(), 16);
+        for host in &["..", "a/b", "a\\b", "a..b:80"] {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            let request = format!(
+                "GET / HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                host
+            );
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let response = read_all(stream).await;
+            assert!(response.starts_with("HTTP/1.1 400 Bad Request"), "{}", host);
+        }
+    }
+
+    #[tokio::test]
+    async fn requires_a_host_in_http_1_1() {
+        let (address, _, _) = start(Server::default(), 16);
+        let get = |request: &'static str| async move {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            stream.write_all(request.as_bytes()).await.unwrap();
+            read_all(stream).await
+        };
+        let response = get("GET / HTTP/1.1\r\nConnection: close\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+        let response = get("GET / HTTP/1.0\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+    }
+
+    #[tokio::test]
+    async fn answers_bad_requests_like_the_request_line_asks() {
+        let (address, _, _) = start(Server::default(), 16);
+        let get = |request: &'static str| async move {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            stream.write_all(request.as_bytes()).await.unwrap();
+            read_all(stream).await
+        };
+        // the head of a HEAD request only
+        let response = get("HEAD / HTTP/1.1\r\nHost: a\r\nBad Header\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+        assert!(response.ends_with("\r\n\r\n"));
+        assert!(!response.contains("<html>"));
+        // a request line that couldn't be read is answered as a GET
+        let response = get("HE AD / HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+        assert!(response.ends_with("</html>\r\n"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn closes_connections_stalled_in_a_body() {
+        let server = Server {
+            keepalive: KeepAlive {
+                timeout: Duration::from_millis(300),
+                requests: 100,
+            },
+            ..Server::default()
+        };
+        let (address, connections, _) = start(server, 16);
+        let post = "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 10\r\n\r\nhalf!";
+
+        // closed once the rest of the body takes longer than the next request
+        // may
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(post.as_bytes()).await.unwrap();
+        assert!(read_page(&mut stream)
+            .await
+            .starts_with("HTTP/1.1 405 Not Allowed"));
+        let stalled = Instant::now();
+        assert_eq!(read_all(stream).await, "");
+        assert!(stalled.elapsed() >= Duration::from_millis(200));
+
+        // and at once on shutdown
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(post.as_bytes()).await.unwrap();
+        read_page(&mut stream).await;
+        connections.shutdown();
+        timeout(Duration::from_millis(200), connections.drained())
+            .await
+            .unwrap();
+        assert_eq!(read_all(stream).await, "");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn limits_and_drains_connections() {
+        let (address, connections, accepting) = start(Server::default(), 1);
+
+        let mut first = TcpStream::connect(address).await.unwrap();
+        first.write_all(b"GET / HTTP/1.1\r\nHo").await.unwrap();
+        wait_for(|| connections.active() == 1).await;
+        // the second one waits in the backlog until the first is done
+        let mut second = TcpStream::connect(address).await.unwrap();
+        second
+            .write_all(b"GET / HTTP/1.1\r\nHost: b\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut byte = [0; 1];
+        assert!(timeout(Duration::from_millis(100), second.read(&mut byte))
+            .await
+            .is_err());
+        assert_eq!(connections.accepted(), 1);
+        first
+            .write_all(b"st: a\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        assert!(read_all(first).await.starts_with("HTTP/1.1 404 Not Found"));
+        assert!(read_all(second).await.starts_with("HTTP/1.1 404 Not Found"));
+        wait_for(|| connections.handled() == 2).await;
+        assert_eq!((connections.active(), connections.accepted()), (0, 2));
+
+        // open connections are finished after a shutdown, and closed after
+        // the response they're in the middle of
+        let mut third = TcpStream::connect(address).await.unwrap();
+        third.write_all(b"GET / HTTP/1.1\r\nHo").await.unwrap();
+        wait_for(|| connections.active() == 1).await;
+        // one that hasn't started a request yet would be closed
+        sleep(Duration::from_millis(50)).await;
+        connections.shutdown();
+        assert_eq!(accepting.await.unwrap(), Ok(()));
+        assert!(TcpStream::connect(address).await.is_err());
+        assert!(timeout(Duration::from_millis(50), connections.drained())
+            .await
+            .is_err());
+        third.write_all(b"st: a\r\n\r\n").await.unwrap();
+        let response = read_all(third).await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+        assert!(response.contains("Connection: close\r\n"));
+        connections.drained().await;
+        assert_eq!(
+            (
+                connections.active(),
+                connections.accepted(),
+                connections.handled()
+            ),
+            (0, 3, 3)
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn drains_connections_stalled_in_a_body() {
+        let (address, connections, _) = start(Server::default(), 16);
+        // the response is sent before the body is read
+        let mut stalled = TcpStream::connect(address).await.unwrap();
+        stalled
+            .write_all(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 10\r\n\r\nhalf!")
+            .await
+            .unwrap();
+        read_page(&mut stalled).await;
+        let mut busy = TcpStream::connect(address).await.unwrap();
+        busy.write_all(b"GET / HTTP/1.1\r\nHo").await.unwrap();
+        wait_for(|| connections.active() == 2).await;
+        sleep(Duration::from_millis(50)).await;
+
+        connections.shutdown();
+        assert!(timeout(Duration::from_millis(50), connections.drained())
+            .await
+            .is_err());
+        busy.write_all(b"st: a\r\n\r\n").await.unwrap();
+        assert!(read_all(busy).await.starts_with("HTTP/1.1 404 Not Found"));
+        // the stalled body doesn't keep the drain waiting
+        timeout(Duration::from_millis(200), connections.drained())
+            .await
+            .unwrap();
+        assert_eq!(read_all(stalled).await, "");
+        assert_eq!((connections.active(), connections.handled()), (0, 2));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn keeps_connections_alive() {
+        let server = Server {
+            keepalive: KeepAlive {
+                timeout: Duration::from_millis(300),
+                requests: 3,
+            },
+            ..Server::default()
+        };
+        let (address, connections, _) = start(server, 16);
+        let count = |response: &str, pattern: &str| response.matches(pattern).count();
+
+        // pipelined, the third request is the last one the connection takes
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let request = "GET /a HTTP/1.1\r\nHost: a\r\n\r\n";
+        stream
+            .write_all(request.repeat(4).as_bytes())
+            .await
+            .unwrap();
+        let response = read_all(stream).await;
+        assert_eq!(count(&response, "HTTP/1.1 404 Not Found\r\n"), 3);
+        assert_eq!(count(&response, "Connection: keep-alive\r\n"), 2);
+        assert_eq!(count(&response, "Connection: close\r\n"), 1);
+
+        // one after the other, then closed after the timeout
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        for _ in 0..2 {
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let response = read_page(&mut stream).await;
+            assert!(response.contains("Connection: keep-alive\r\n"));
+        }
+        let idle = Instant::now();
+        assert_eq!(read_all(stream).await, "");
+        // the timer started when the response was sent
+        assert!(idle.elapsed() >= Duration::from_millis(200));
+
+        // HTTP/1.0 closes unless asked not to
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let request = "GET /a HTTP/1.0\r\nConnection: keep-alive\r\n\r\n";
+        stream.write_all(request.as_bytes()).await.unwrap();
+        assert!(read_page(&mut stream)
+            .await
+            .contains("Connection: keep-alive\r\n"));
+        stream.write_all(b"GET /a HTTP/1.0\r\n\r\n").await.unwrap();
+        assert!(read_all(stream).await.contains("Connection: close\r\n"));
+
+        // a shutdown closes connections waiting for their next request, or
+        // for their first one
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(b"GET /a HTTP/1.1\r\nHost: a\r\n\r\n")
+            .await
+            .unwrap();
+        read_page(&mut stream).await;
+        let silent = TcpStream::connect(address).await.unwrap();
+        wait_for(|| connections.active() == 2).await;
+        connections.shutdown();
+        timeout(Duration::from_millis(200), connections.drained())
+            .await
+            .unwrap();
+        assert_eq!(read_all(stream).await, "");
+        assert_eq!(read_all(silent).await, "");
+        assert_eq!(connections.handled(), 5);
     }
 }
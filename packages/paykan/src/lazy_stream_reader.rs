@@ -1,9 +1,18 @@
-use chashmap::{CHashMap, ReadGuard};
+use futures::stream::{self, Stream};
 use std::{
-    cell::{Ref, RefCell},
+    error::Error,
+    fmt, io,
     pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    sync::{Mutex as AsyncMutex, OnceCell},
 };
-use tokio::io::{AsyncRead, AsyncReadExt};
 
 /*
 
@@ -16,82 +25,348 @@ Connection: Keep-Alive
 
 */
 
-#[derive(PartialEq, Debug)]
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
 pub enum HttpMethod {
     Get,
     Post,
     Delete,
     Put,
     Head,
+    Options,
+    Patch,
+    Connect,
+    Trace,
+    /// Any other token, like WebDAV's `PROPFIND`. Methods are case-sensitive,
+    /// so `get` is an extension method too.
+    Extension(String),
+}
+
+impl HttpMethod {
+    pub fn as_str(&self) -> &str {
+        match self {
+            HttpMethod::Get => "GET",
+            HttpMethod::Post => "POST",
+            HttpMethod::Delete => "DELETE",
+            HttpMethod::Put => "PUT",
+            HttpMethod::Head => "HEAD",
+            HttpMethod::Options => "OPTIONS",
+            HttpMethod::Patch => "PATCH",
+            HttpMethod::Connect => "CONNECT",
+            HttpMethod::Trace => "TRACE",
+            HttpMethod::Extension(method) => method,
+        }
+    }
+
+    /// The method named `name`, `None` if it isn't a token.
+    pub fn from_bytes(name: &[u8]) -> Option<Self> {
+        if name.is_empty() || !name.iter().all(|&b| is_token(b)) {
+            return None;
+        }
+        Some(match name {
+            b"GET" => HttpMethod::Get,
+            b"POST" => HttpMethod::Post,
+            b"PUT" => HttpMethod::Put,
+            b"DELETE" => HttpMethod::Delete,
+            b"HEAD" => HttpMethod::Head,
+            b"OPTIONS" => HttpMethod::Options,
+            b"PATCH" => HttpMethod::Patch,
+            b"CONNECT" => HttpMethod::Connect,
+            b"TRACE" => HttpMethod::Trace,
+            // tokens are ASCII
+            _ => HttpMethod::Extension(String::from_utf8_lossy(name).into_owned()),
+        })
+    }
+}
+
+impl FromStr for HttpMethod {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        HttpMethod::from_bytes(value.as_bytes())
+            .ok_or_else(|| format!("invalid method \"{}\"", value))
+    }
+}
+
+impl fmt::Display for HttpMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
+#[derive(Clone, Copy, PartialEq, PartialOrd, Debug)]
 pub enum HttpVersion {
-    Http0_9 = 09,
+    Http0_9 = 9,
     Http1_0 = 10,
     Http1_1 = 11,
     Http2_0 = 20,
 }
 
+impl HttpVersion {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HttpVersion::Http0_9 => "HTTP/0.9",
+            HttpVersion::Http1_0 => "HTTP/1.0",
+            HttpVersion::Http1_1 => "HTTP/1.1",
+            HttpVersion::Http2_0 => "HTTP/2.0",
+        }
+    }
+}
+
+/// Why a request couldn't be read. Once reading a part fails the stream is
+/// out of step with the request, so every part after it fails the same way.
+#[derive(Clone, Debug)]
+pub enum HttpParseError {
+    BadMethod(String),
+    /// The request target is empty, has a control character or isn't UTF-8.
+    BadUri,
+    UriTooLong,
+    /// The version isn't `HTTP/x.y`.
+    BadVersion(String),
+    /// `HTTP/x.y` with a major version we don't speak.
+    UnsupportedVersion(String),
+    MalformedHeader(String),
+    /// A line of the request line or the headers ended in a bare LF.
+    BadLineEnding,
+    HeadersTooLarge,
+    /// The connection was closed in the middle of the request.
+    UnexpectedEof,
+    Io(Arc<io::Error>),
+}
+
+impl fmt::Display for HttpParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpParseError::BadMethod(method) => write!(f, "invalid method {:?}", method),
+            HttpParseError::BadUri => write!(f, "invalid request target"),
+            HttpParseError::UriTooLong => write!(f, "request target is too long"),
+            HttpParseError::BadVersion(version) => write!(f, "invalid version {:?}", version),
+            HttpParseError::UnsupportedVersion(version) => {
+                write!(f, "unsupported version {:?}", version)
+            }
+            HttpParseError::MalformedHeader(line) => write!(f, "malformed header {:?}", line),
+            HttpParseError::BadLineEnding => write!(f, "line isn't ended by CRLF"),
+            HttpParseError::HeadersTooLarge => write!(f, "headers are too large"),
+            HttpParseError::UnexpectedEof => {
+                write!(f, "connection closed before the request ended")
+            }
+            HttpParseError::Io(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl Error for HttpParseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            HttpParseError::Io(error) => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for HttpParseError {
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof => HttpParseError::UnexpectedEof,
+            _ => HttpParseError::Io(Arc::new(error)),
+        }
+    }
+}
+
+impl From<HttpParseError> for io::Error {
+    fn from(error: HttpParseError) -> Self {
+        match error {
+            HttpParseError::Io(error) => io::Error::new(error.kind(), error.to_string()),
+            HttpParseError::UnexpectedEof => io::ErrorKind::UnexpectedEof.into(),
+            error => io::Error::new(io::ErrorKind::InvalidData, error),
+        }
+    }
+}
+
+/// What was parsed so far. The request line is set once, the rest is only
+/// changed by the task holding the stream, and none of the locks are held
+/// across an `.await`.
 #[derive(Default)]
 struct Inner {
-    method: RefCell<Option<HttpMethod>>,
-    resource: RefCell<Option<String>>,
-    version: RefCell<Option<HttpVersion>>,
-    headers: CHashMap<String, String>,
-    headers_finished: bool,
+    method: OnceCell<HttpMethod>,
+    resource: OnceCell<String>,
+    version: OnceCell<HttpVersion>,
+    /// The headers read so far in the order they were sent, names are
+    /// compared case-insensitively.
+    headers: Mutex<Vec<(String, String)>>,
+    headers_finished: AtomicBool,
+    /// The bytes of the header lines read so far.
+    headers_len: AtomicUsize,
+    body: Mutex<Option<BodyState>>,
+    trailers: Mutex<Vec<(String, String)>>,
+    error: Mutex<Option<HttpParseError>>,
+}
+
+/// Where reading the body is at.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum BodyState {
+    /// The bytes left of a `Content-Length` body.
+    Length(u64),
+    /// Before the size line of a chunk.
+    ChunkSize,
+    /// The bytes left of a chunk.
+    ChunkData(u64),
+    /// Before the CRLF that ends a chunk.
+    ChunkEnd,
+    Done,
 }
 
+const MAX_METHOD: usize = 32;
+const MAX_URI: usize = 8 * 1024;
+const MAX_VERSION: usize = 16;
+/// A header line longer than this, or all of them together longer than
+/// `MAX_HEADERS`, is answered with 431.
+const MAX_HEADER_LINE: usize = 8 * 1024;
+const MAX_HEADERS: usize = 32 * 1024;
+/// Chunk size lines with extensions and trailer lines longer than this are
+/// rejected.
+const MAX_LINE: usize = 4096;
+/// How much of a body left unread is skipped to get to the next request.
+const MAX_DISCARD: usize = 64 * 1024;
+
+/// A request read lazily from a stream, part by part as they're asked for.
+/// It's `Send` and `Sync`, so it can be shared by the tasks of a connection;
+/// the one reading from the stream holds it and the others wait.
 pub struct HttpLazyStreamReader {
-    stream: RefCell<AsyncReadStream>,
+    stream: AsyncMutex<AsyncReadStream>,
     inner: Inner,
 }
 
 struct AsyncReadStream {
-    stream: Pin<Box<dyn AsyncRead>>,
+    stream: Pin<Box<dyn AsyncRead + Send>>,
     buff: [u8; 1024],
     cursor: usize,
     max_cursor: usize,
     finished: bool,
+    allow_bare_lf: bool,
 }
 
 impl AsyncReadStream {
-    pub fn new(stream: Pin<Box<dyn AsyncRead>>) -> Self {
+    pub fn new(stream: Pin<Box<dyn AsyncRead + Send>>) -> Self {
         Self {
             buff: [0u8; 1024],
             stream,
             cursor: 0,
             max_cursor: 0,
             finished: false,
+            allow_bare_lf: false,
         }
     }
 }
 
 impl AsyncReadStream {
-    #[inline(always)]
-    async fn next(&mut self) -> Option<u8> {
+    /// Reads more into the buffer if it's all used. `false` at the end of
+    /// the stream.
+    async fn fill(&mut self) -> io::Result<bool> {
+        if self.cursor < self.max_cursor {
+            return Ok(true);
+        }
         if self.finished {
-            return None;
+            return Ok(false);
         }
-        if self.cursor >= self.max_cursor {
-            let mut buff = [0u8; 1024];
-            let n = match self.stream.read(&mut buff).await {
-                Ok(x) => x,
-                _ => 0,
-            };
-            if n == 0 {
-                self.finished = true;
-                return None;
-            }
-            self.buff = buff;
-            let item = self.buff[0];
-            self.cursor = 1;
-            self.max_cursor = n - 1;
-            return Some(item);
+        let n = self.stream.read(&mut self.buff).await?;
+        if n == 0 {
+            self.finished = true;
+            return Ok(false);
+        }
+        self.cursor = 0;
+        self.max_cursor = n;
+        Ok(true)
+    }
+
+    #[inline(always)]
+    async fn next(&mut self) -> io::Result<Option<u8>> {
+        if !self.fill().await? {
+            return Ok(None);
         }
         let item = self.buff[self.cursor];
         self.cursor += 1;
-        return Some(item);
+        Ok(Some(item))
     }
+
+    /// Up to `max` bytes, from the buffer if it has any. Empty at the end of
+    /// the stream.
+    async fn take(&mut self, max: usize) -> io::Result<Vec<u8>> {
+        if max == 0 {
+            return Ok(Vec::new());
+        }
+        let first = match self.next().await? {
+            Some(first) => first,
+            None => return Ok(Vec::new()),
+        };
+        let end = self.max_cursor.min(self.cursor + max - 1);
+        let mut bytes = Vec::with_capacity(end - self.cursor + 1);
+        bytes.push(first);
+        bytes.extend_from_slice(&self.buff[self.cursor..end]);
+        self.cursor = end;
+        Ok(bytes)
+    }
+
+    /// A line without its LF, `None` if it's longer than `limit` bytes. See
+    /// [`strip_cr`](Self::strip_cr) for the CR.
+    async fn line(&mut self, limit: usize) -> io::Result<Option<Vec<u8>>> {
+        let mut line = Vec::new();
+        loop {
+            match self.next().await? {
+                None => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Some(b'\n') => return Ok(Some(line)),
+                Some(_) if line.len() >= limit => return Ok(None),
+                Some(b) => line.push(b),
+            }
+        }
+    }
+
+    /// Removes the CR of the CRLF that ended `line`. Whether the line was
+    /// ended properly, a bare LF is only accepted with `allow_bare_lf`.
+    fn strip_cr(&self, line: &mut Vec<u8>) -> bool {
+        if line.last() == Some(&b'\r') {
+            line.pop();
+            return true;
+        }
+        self.allow_bare_lf
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Whether `b` can be in a token, like a method or a header name.
+pub fn is_token(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+/// Splits a `name: value` header or trailer line, without the whitespace
+/// around the value. A line starting with whitespace would continue the
+/// previous one with the obsolete line folding, which isn't accepted, and
+/// neither are control characters in the value.
+fn parse_field(line: &[u8]) -> Result<(String, String), HttpParseError> {
+    let malformed = || HttpParseError::MalformedHeader(String::from_utf8_lossy(line).into_owned());
+    // the value may contain ':' too, e.g. `Host: example.com:8080`
+    let index = line.iter().position(|&b| b == b':').ok_or_else(malformed)?;
+    let (name, value) = (&line[..index], &line[index + 1..]);
+    if name.is_empty() || !name.iter().all(|&b| is_token(b)) {
+        return Err(malformed());
+    }
+    let is_space = |b: &u8| *b == b' ' || *b == b'\t';
+    let start = value
+        .iter()
+        .position(|b| !is_space(b))
+        .unwrap_or(value.len());
+    let end = value
+        .iter()
+        .rposition(|b| !is_space(b))
+        .map_or(start, |end| end + 1);
+    let value = &value[start..end];
+    if value.iter().any(|&b| (b < b' ' && b != b'\t') || b == 0x7f) {
+        return Err(malformed());
+    }
+    let value = String::from_utf8(value.to_vec()).map_err(|_| malformed())?;
+    Ok((String::from_utf8_lossy(name).into_owned(), value))
 }
 
 macro_rules! add_part {
@@ -101,66 +376,109 @@ macro_rules! add_part {
         Before: $before: tt,
         Parser: |$stream: ident| $parser: expr,
     ) => {
-        pub async fn $name(&self) -> Ref<'_, $ret> {
-            let inner = self.inner.$name.borrow();
-            if inner.is_some() {
-                return Ref::map(inner, |x| x.as_ref().unwrap());
-            }
-            drop(inner);
-            add_part!(@check-before self, $before);
-            #[allow(unused_mut)]
-            let mut $stream = self.stream.borrow_mut();
-            let result = $parser;
-            let result = result.await;
-            drop($stream);
-            {
-                let inner = &mut self.inner.$name.borrow_mut();
-                **inner = Some(result);
-                // decrement the mut count
-            }
-            let inner = self.inner.$name.borrow();
-            return Ref::map(inner, |x| x.as_ref().unwrap());
+        pub async fn $name(&self) -> Result<&$ret, HttpParseError> {
+            // only one task at a time runs the parser, the others wait for it
+            self.inner
+                .$name
+                .get_or_try_init(|| async {
+                    self.check_error()?;
+                    add_part!(@check-before self, $before);
+                    let mut $stream = self.stream.lock().await;
+                    let result = $parser.await;
+                    self.record(result)
+                })
+                .await
         }
     };
     (@check-before $rec:ident, None) => {};
-    (@check-before $rec:ident, $before: ident) => {{
-        let no_before = $rec.inner.$before.borrow().is_none();
-        if no_before {
-            drop($rec.$before().await);
-        }
-    }}
+    (@check-before $rec:ident, $before: ident) => {
+        $rec.$before().await?;
+    }
 }
 
 impl HttpLazyStreamReader {
-    pub fn new(stream: Pin<Box<dyn AsyncRead>>) -> Self {
+    pub fn new(stream: Pin<Box<dyn AsyncRead + Send>>) -> Self {
         let stream_reader = AsyncReadStream::new(stream);
         Self {
-            stream: RefCell::new(stream_reader),
+            stream: AsyncMutex::new(stream_reader),
+            inner: Inner::default(),
+        }
+    }
+
+    /// Also accepts lines ended by a bare LF instead of CRLF, as RFC 7230
+    /// allows, for requests typed by hand. Off by default.
+    pub fn allow_bare_lf(mut self, allow: bool) -> Self {
+        self.stream.get_mut().allow_bare_lf = allow;
+        self
+    }
+
+    /// Waits for the first byte of the request, `false` if the connection was
+    /// closed before it. Tells a client that's done with a connection from
+    /// one that left in the middle of a request.
+    pub async fn started(&self) -> Result<bool, HttpParseError> {
+        let mut stream = self.stream.lock().await;
+        Ok(stream.fill().await?)
+    }
+
+    /// The reader for the request after this one on the same connection,
+    /// once what's left of this one's body is skipped. Requests the client
+    /// sent without waiting for the response are read from the buffer.
+    /// `None` when more than `MAX_DISCARD` of the body is left, the
+    /// connection is better closed than read to the end then.
+    pub async fn next_request(self) -> Result<Option<HttpLazyStreamReader>, HttpParseError> {
+        let mut body = self.body().await?;
+        let mut discarded = 0;
+        while let Some(chunk) = body.chunk().await? {
+            discarded += chunk.len();
+            if discarded > MAX_DISCARD {
+                return Ok(None);
+            }
+        }
+        Ok(Some(Self {
+            stream: AsyncMutex::new(self.stream.into_inner()),
             inner: Inner::default(),
+        }))
+    }
+
+    /// The error an earlier part failed with.
+    fn check_error(&self) -> Result<(), HttpParseError> {
+        match &*self.inner.error.lock().unwrap() {
+            Some(error) => Err(error.clone()),
+            None => Ok(()),
         }
     }
 
+    /// Keeps the error of `result` for the parts read after it.
+    fn record<T>(&self, result: Result<T, HttpParseError>) -> Result<T, HttpParseError> {
+        if let Err(error) = &result {
+            *self.inner.error.lock().unwrap() = Some(error.clone());
+        }
+        result
+    }
+
     add_part!(
         Name: method,
         Type: HttpMethod,
         Before: None,
         Parser: |stream| async {
             let mut method = Vec::with_capacity(10);
-            while let Some(b) = stream.next().await {
-                match b {
-                    0 => panic!(""),
-                    b' ' => break,
-                    x => method.push(x),
+            loop {
+                match stream.next().await? {
+                    None => return Err(HttpParseError::UnexpectedEof),
+                    // empty lines before the request line are ignored
+                    Some(b'\r') | Some(b'\n') if method.is_empty() => {}
+                    Some(b' ') => break,
+                    Some(x) if is_token(x) && method.len() < MAX_METHOD => method.push(x),
+                    Some(x) => {
+                        method.push(x);
+                        let method = String::from_utf8_lossy(&method).into_owned();
+                        return Err(HttpParseError::BadMethod(method));
+                    }
                 }
             }
-            match &method[..] {
-                b"GET" => HttpMethod::Get,
-                b"POST" => HttpMethod::Post,
-                b"PUT" => HttpMethod::Put,
-                b"DELETE" => HttpMethod::Delete,
-                b"HEAD" => HttpMethod::Head,
-                _ => panic!(""),
-            }
+            HttpMethod::from_bytes(&method).ok_or_else(|| {
+                HttpParseError::BadMethod(String::from_utf8_lossy(&method).into_owned())
+            })
         },
     );
 
@@ -170,14 +488,18 @@ impl HttpLazyStreamReader {
         Before: method,
         Parser: |stream| async {
             let mut resource = Vec::with_capacity(10);
-            while let Some(b) = stream.next().await {
-                match b {
-                    0 => panic!(""),
-                    b' ' => break,
-                    x => resource.push(x),
+            loop {
+                match stream.next().await? {
+                    None => return Err(HttpParseError::UnexpectedEof),
+                    Some(b' ') if !resource.is_empty() => break,
+                    Some(x) if x <= b' ' || x == 0x7f => return Err(HttpParseError::BadUri),
+                    Some(_) if resource.len() >= MAX_URI => {
+                        return Err(HttpParseError::UriTooLong)
+                    }
+                    Some(x) => resource.push(x),
                 }
             }
-            String::from_utf8(resource).unwrap()
+            String::from_utf8(resource).map_err(|_| HttpParseError::BadUri)
         },
     );
 
@@ -186,104 +508,360 @@ impl HttpLazyStreamReader {
         Type: HttpVersion,
         Before: resource,
         Parser: |stream| async {
-            let mut version = Vec::with_capacity(10);
-            while let Some(b) = stream.next().await {
-                match b {
-                    0 => panic!(""),
-                    b'\n' => break,
-                    x => version.push(x),
-                }
+            let mut version = stream
+                .line(MAX_VERSION)
+                .await?
+                .ok_or_else(|| HttpParseError::BadVersion(String::new()))?;
+            if !stream.strip_cr(&mut version) {
+                return Err(HttpParseError::BadLineEnding);
             }
+            let text = String::from_utf8_lossy(&version).into_owned();
             match &version[..] {
-                b"HTTP/0.9" => HttpVersion::Http0_9,
-                b"HTTP/1.0" => HttpVersion::Http1_0,
-                b"HTTP/1.1" => HttpVersion::Http1_1,
-                _ => panic!("{:?} is not supported", &version),
+                b"HTTP/0.9" => Ok(HttpVersion::Http0_9),
+                b"HTTP/1.0" => Ok(HttpVersion::Http1_0),
+                // a later 1.x is understood by a 1.1 server
+                [b'H', b'T', b'T', b'P', b'/', b'1', b'.', minor] if minor.is_ascii_digit() => {
+                    Ok(HttpVersion::Http1_1)
+                }
+                [b'H', b'T', b'T', b'P', b'/', major, b'.', minor]
+                    if major.is_ascii_digit() && minor.is_ascii_digit() =>
+                {
+                    Err(HttpParseError::UnsupportedVersion(text))
+                }
+                _ => Err(HttpParseError::BadVersion(text)),
             }
         },
     );
 
-    async fn header_inner<'a>(
-        &'a self,
+    /// Reads header lines until the one named `name` unless `get_all`, or
+    /// until the end of the headers. Whether `name` was found.
+    async fn read_headers(
+        &self,
+        stream: &mut AsyncReadStream,
         name: &str,
         get_all: bool,
-    ) -> Option<ReadGuard<'a, String, String>> {
-        {
-            /* return value if pressent */
-            let val = self.inner.headers.get(name);
-            if val.is_some() {
-                return val;
+    ) -> Result<bool, HttpParseError> {
+        loop {
+            let mut line = stream
+                .line(MAX_HEADER_LINE)
+                .await?
+                .ok_or(HttpParseError::HeadersTooLarge)?;
+            let headers_len = self.inner.headers_len.load(Ordering::Acquire) + line.len() + 1;
+            if headers_len > MAX_HEADERS {
+                return Err(HttpParseError::HeadersTooLarge);
+            }
+            self.inner.headers_len.store(headers_len, Ordering::Release);
+            if !stream.strip_cr(&mut line) {
+                return Err(HttpParseError::BadLineEnding);
+            }
+            // an empty line ends the headers
+            if line.is_empty() {
+                self.inner.headers_finished.store(true, Ordering::Release);
+                return Ok(false);
+            }
+            let (header_name, header_value) = parse_field(&line)?;
+            let mut headers = self.inner.headers.lock().unwrap();
+            // which of two hosts a request is for is anyone's guess
+            if header_name.eq_ignore_ascii_case("host")
+                && headers
+                    .iter()
+                    .any(|(name, _)| name.eq_ignore_ascii_case("host"))
+            {
+                return Err(HttpParseError::MalformedHeader(
+                    String::from_utf8_lossy(&line).into_owned(),
+                ));
+            }
+            let found = !get_all && header_name.eq_ignore_ascii_case(name);
+            headers.push((header_name, header_value));
+            if found {
+                return Ok(true);
             }
+        }
+    }
+
+    fn find_header(&self, name: &str) -> Option<String> {
+        let headers = self.inner.headers.lock().unwrap();
+        headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.clone())
+    }
+
+    /// Reads headers until `name` unless `get_all`, in which case they are
+    /// all read and `None` is returned.
+    async fn header_inner(
+        &self,
+        name: &str,
+        get_all: bool,
+    ) -> Result<Option<String>, HttpParseError> {
+        // what's been read may already answer it
+        let read = || match self.find_header(name) {
+            Some(value) if !get_all => Some(Some(value)),
+            _ if self.inner.headers_finished.load(Ordering::Acquire) => Some(None),
+            _ => None,
         };
-        if self.inner.headers_finished {
-            return None;
+        if let Some(value) = read() {
+            return Ok(value);
         }
+        self.check_error()?;
         // we should've parsed until http version
         add_part!(@check-before self, version);
 
-        let mut stream = self.stream.borrow_mut();
-        let mut has_more_headers = true;
-        'outer: loop {
-            let mut header_name = Vec::with_capacity(10);
-            let mut header_value = Vec::with_capacity(10);
-            let mut is_name = true;
-            let mut is_start = true;
-            'inner: while let Some(b) = stream.next().await {
-                let container = if is_name {
-                    &mut header_name
-                } else {
-                    &mut header_value
-                };
-                match b {
-                    0 => panic!(""),
-                    b'\n' => {
-                        if is_start {
-                            has_more_headers = false;
-                        } else {
-                            // header row is finished
-                            break 'inner;
-                        }
+        let mut stream = self.stream.lock().await;
+        // another task may have read more while this one waited
+        if let Some(value) = read() {
+            return Ok(value);
+        }
+        self.check_error()?;
+        let result = self.read_headers(&mut stream, name, get_all).await;
+        drop(stream);
+        match self.record(result)? {
+            true => Ok(self.find_header(name)),
+            false => Ok(None),
+        }
+    }
+
+    /// The first value of the header `name`, whatever its case. Only the
+    /// headers up to it are read.
+    pub async fn header(&self, name: &str) -> Result<Option<String>, HttpParseError> {
+        self.header_inner(name, false).await
+    }
+
+    /// Every value of the header `name` in the order they were sent, for
+    /// headers that can be repeated like `Via` or `Accept`.
+    pub async fn headers_all(&self, name: &str) -> Result<Vec<String>, HttpParseError> {
+        self.header_inner(name, true).await?;
+        let headers = self.inner.headers.lock().unwrap();
+        Ok(headers
+            .iter()
+            .filter(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.clone())
+            .collect())
+    }
+
+    /// Every header as `(name, value)`, in the order they were sent.
+    pub async fn headers(&self) -> Result<impl Iterator<Item = (String, String)>, HttpParseError> {
+        self.header_inner("", true).await?;
+        let headers = self.inner.headers.lock().unwrap().clone();
+        Ok(headers.into_iter())
+    }
+
+    /// Whether the client wants the connection kept open after the response:
+    /// in HTTP/1.1 unless it sent `Connection: close`, in HTTP/1.0 only with
+    /// `Connection: keep-alive`.
+    pub async fn keep_alive(&self) -> Result<bool, HttpParseError> {
+        let version = *self.version().await?;
+        let connection = self.headers_all("Connection").await?;
+        let has = |option: &str| {
+            connection
+                .iter()
+                .flat_map(|value| value.split(','))
+                .any(|value| value.trim().eq_ignore_ascii_case(option))
+        };
+        Ok(match version {
+            HttpVersion::Http1_1 => !has("close"),
+            HttpVersion::Http1_0 => has("keep-alive") && !has("close"),
+            _ => false,
+        })
+    }
+
+    /// How the body is framed, from `Content-Length` and
+    /// `Transfer-Encoding`. A request with both, with `Content-Length`s that
+    /// differ or with a coding other than `chunked` is rejected: a proxy in
+    /// front of us could see a different body and smuggle a request in.
+    async fn body_state(&self) -> Result<BodyState, HttpParseError> {
+        let content_length = self.headers_all("Content-Length").await?;
+        let transfer_encoding = self.headers_all("Transfer-Encoding").await?;
+        if !transfer_encoding.is_empty() {
+            if !content_length.is_empty() {
+                return Err(HttpParseError::MalformedHeader(
+                    "both Content-Length and Transfer-Encoding are set".to_string(),
+                ));
+            }
+            if *self.version().await? == HttpVersion::Http1_0 {
+                return Err(HttpParseError::MalformedHeader(
+                    "Transfer-Encoding isn't supported in HTTP/1.0".to_string(),
+                ));
+            }
+            let codings = transfer_encoding
+                .iter()
+                .flat_map(|value| value.split(','))
+                .map(str::trim)
+                .filter(|coding| !coding.is_empty())
+                .collect::<Vec<_>>();
+            return match &codings[..] {
+                [coding] if coding.eq_ignore_ascii_case("chunked") => Ok(BodyState::ChunkSize),
+                _ => Err(HttpParseError::MalformedHeader(
+                    "unsupported Transfer-Encoding".to_string(),
+                )),
+            };
+        }
+        let invalid_length = || HttpParseError::MalformedHeader(content_length.join(", "));
+        let mut length = None;
+        for value in content_length.iter().flat_map(|value| value.split(',')) {
+            let value = value.trim();
+            if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(invalid_length());
+            }
+            let value: u64 = value.parse().map_err(|_| invalid_length())?;
+            if length.is_some_and(|length| length != value) {
+                return Err(HttpParseError::MalformedHeader(
+                    "conflicting Content-Length values".to_string(),
+                ));
+            }
+            length = Some(value);
+        }
+        Ok(BodyState::Length(length.unwrap_or(0)))
+    }
+
+    /// The request body, read lazily as it's consumed. The headers are read
+    /// first if they weren't, and a request without `Content-Length` or
+    /// `Transfer-Encoding` has an empty body. Calling this again continues
+    /// where the previous [`Body`] stopped.
+    pub async fn body(&self) -> Result<Body<'_>, HttpParseError> {
+        let state = *self.inner.body.lock().unwrap();
+        if state.is_none() {
+            let state = self.body_state().await;
+            let state = self.record(state)?;
+            // keep where another task got to if it was faster
+            self.inner.body.lock().unwrap().get_or_insert(state);
+        }
+        Ok(Body { reader: self })
+    }
+}
+
+/// The body of a request, see [`HttpLazyStreamReader::body`].
+pub struct Body<'a> {
+    reader: &'a HttpLazyStreamReader,
+}
+
+impl<'a> Body<'a> {
+    /// The next part of the body, `None` once it's all read.
+    pub async fn chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let result = self.next_chunk().await;
+        if result.is_err() {
+            // the stream is out of sync, nothing after this can be read
+            *self.reader.inner.body.lock().unwrap() = Some(BodyState::Done);
+        }
+        result
+    }
+
+    async fn next_chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let inner = &self.reader.inner;
+        let mut stream = self.reader.stream.lock().await;
+        loop {
+            let state = inner.body.lock().unwrap().unwrap_or(BodyState::Done);
+            let next = match state {
+                BodyState::Done | BodyState::Length(0) => BodyState::Done,
+                BodyState::Length(remaining) | BodyState::ChunkData(remaining) => {
+                    let data = stream.take(remaining.min(16 * 1024) as usize).await?;
+                    if data.is_empty() {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
                     }
-                    b'\r' => {
-                        // header is finished
-                        break 'outer;
+                    let remaining = remaining - data.len() as u64;
+                    *inner.body.lock().unwrap() = Some(match state {
+                        BodyState::Length(_) => BodyState::Length(remaining),
+                        _ if remaining == 0 => BodyState::ChunkEnd,
+                        _ => BodyState::ChunkData(remaining),
+                    });
+                    return Ok(Some(data));
+                }
+                BodyState::ChunkEnd => match stream.line(1).await? {
+                    Some(line) if line == b"\r" || (line.is_empty() && stream.allow_bare_lf) => {
+                        BodyState::ChunkSize
                     }
-                    x => {
-                        if !has_more_headers {
-                            panic!("expected '\\r' found '{}'", x);
-                        }
-                        is_start = false;
-                        if x != b':' {
-                            container.push(x);
-                        } else {
-                            is_name = false;
-                        }
+                    _ => return Err(invalid("missing CRLF after chunk data")),
+                },
+                BodyState::ChunkSize => {
+                    let mut line = stream
+                        .line(MAX_LINE)
+                        .await?
+                        .ok_or_else(|| invalid("line too long"))?;
+                    if !stream.strip_cr(&mut line) {
+                        return Err(HttpParseError::BadLineEnding.into());
                     }
-                };
-            }
-            let header_name = String::from_utf8(header_name).unwrap();
-            let found = !get_all && header_name == name;
-            let header_value = String::from_utf8(header_value).unwrap();
-            self.inner.headers.insert(header_name, header_value);
-            if found {
-                let val = self.inner.headers.get(name);
-                return val;
+                    // extensions after `;` carry nothing we use
+                    let size = line.split(|&b| b == b';').next().unwrap_or_default();
+                    let size = std::str::from_utf8(size)
+                        .ok()
+                        .map(|size| size.trim_end_matches([' ', '\t']))
+                        .filter(|size| {
+                            !size.is_empty() && size.bytes().all(|b| b.is_ascii_hexdigit())
+                        })
+                        .and_then(|size| u64::from_str_radix(size, 16).ok())
+                        .ok_or_else(|| invalid("invalid chunk size"))?;
+                    if size > 0 {
+                        BodyState::ChunkData(size)
+                    } else {
+                        let trailers = read_trailers(&mut stream).await?;
+                        inner.trailers.lock().unwrap().extend(trailers);
+                        BodyState::Done
+                    }
+                }
+            };
+            *inner.body.lock().unwrap() = Some(next);
+            if next == BodyState::Done {
+                return Ok(None);
             }
         }
+    }
 
-        None
+    /// The rest of the body.
+    pub async fn read_to_end(&mut self) -> io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        while let Some(chunk) = self.chunk().await? {
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body)
     }
 
-    pub async fn header<'a>(&'a self, name: &str) -> Option<ReadGuard<'a, String, String>> {
-        self.header_inner(name, false).await
+    /// The fields sent after a chunked body, once it's all read.
+    pub fn trailers(&self) -> Vec<(String, String)> {
+        self.reader.inner.trailers.lock().unwrap().clone()
     }
 
-    // TODO: add body support
+    /// The rest of the body as a stream of its parts, which ends after the
+    /// first error.
+    pub fn into_stream(self) -> impl Stream<Item = io::Result<Vec<u8>>> + 'a {
+        stream::unfold(Some(self), |body| async move {
+            let mut body = body?;
+            match body.chunk().await {
+                Ok(Some(chunk)) => Some((Ok(chunk), Some(body))),
+                Ok(None) => None,
+                Err(error) => Some((Err(error), None)),
+            }
+        })
+    }
+}
+
+/// Reads the trailer lines after the last chunk, all of them together may be
+/// as long as the headers.
+async fn read_trailers(stream: &mut AsyncReadStream) -> io::Result<Vec<(String, String)>> {
+    let mut trailers = Vec::new();
+    let mut trailers_len = 0;
+    loop {
+        let mut line = stream
+            .line(MAX_LINE)
+            .await?
+            .ok_or_else(|| invalid("line too long"))?;
+        trailers_len += line.len() + 1;
+        if trailers_len > MAX_HEADERS {
+            return Err(HttpParseError::HeadersTooLarge.into());
+        }
+        if !stream.strip_cr(&mut line) {
+            return Err(HttpParseError::BadLineEnding.into());
+        }
+        if line.is_empty() {
+            return Ok(trailers);
+        }
+        trailers.push(parse_field(&line)?);
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use futures::StreamExt;
     use paste::paste;
     use std::task::Poll;
 
@@ -301,19 +879,476 @@ mod tests {
         }
     }
 
+    /// A request that can't be read and a check of the error it fails with.
+    type ErrorCase<'a> = (&'a [u8], fn(&HttpParseError) -> bool);
+
     #[tokio::test]
     async fn test_method_can_pattern_match() {
         let payload = format!("{} /hello.htm HTTP/1.1", "GET");
         let payload = payload.as_bytes();
         let mock_read = MockRead(payload.to_vec());
         let reader = HttpLazyStreamReader::new(Box::pin(mock_read));
-        let result = reader.method().await;
+        let result = reader.method().await.unwrap();
         if let HttpMethod::Get = *result {
         } else {
-            assert!(false, "should match");
+            panic!("should match");
         }
     }
 
+    #[tokio::test]
+    async fn test_crlf_headers() {
+        let payload = b"GET / HTTP/1.1\r\nHost: example.com:8080\r\nAccept: */*\r\n\r\n";
+        let reader = HttpLazyStreamReader::new(Box::pin(MockRead(payload.to_vec())));
+        assert_eq!(*reader.version().await.unwrap(), HttpVersion::Http1_1);
+        assert_eq!(
+            reader.header("Host").await.unwrap().unwrap(),
+            "example.com:8080"
+        );
+        assert_eq!(reader.header("Accept").await.unwrap().unwrap(), "*/*");
+        assert!(reader.header("Cookie").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_header_storage() {
+        let payload = b"GET / HTTP/1.1\r\nHost: a\r\nAccept: text/html\r\n\
+            via: 1.0 fred\r\nACCEPT: */*\r\nVia: 1.1 p.example.com\r\n\r\n";
+        let reader = request(payload);
+        // the first value, whatever the case of the name
+        assert_eq!(reader.header("accept").await.unwrap().unwrap(), "text/html");
+        assert_eq!(reader.header("HOST").await.unwrap().unwrap(), "a");
+        assert_eq!(
+            reader.headers_all("Accept").await.unwrap(),
+            ["text/html", "*/*"]
+        );
+        assert_eq!(
+            reader.headers_all("VIA").await.unwrap(),
+            ["1.0 fred", "1.1 p.example.com"]
+        );
+        assert!(reader.headers_all("Cookie").await.unwrap().is_empty());
+        let names: Vec<_> = reader
+            .headers()
+            .await
+            .unwrap()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, ["Host", "Accept", "via", "ACCEPT", "Via"]);
+    }
+
+    #[tokio::test]
+    async fn test_header_whitespace() {
+        let payload = b"GET / HTTP/1.1\r\nA:value\r\nB: \t spaced  out \t\r\nC:\r\nD:   \r\n\r\n";
+        let reader = request(payload);
+        let headers: Vec<_> = reader.headers().await.unwrap().collect();
+        let expected = [("A", "value"), ("B", "spaced  out"), ("C", ""), ("D", "")];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        assert_eq!(headers, expected);
+    }
+
+    #[tokio::test]
+    async fn test_line_endings() {
+        use HttpParseError::*;
+        let cases: &[ErrorCase] = &[
+            (b"GET / HTTP/1.1\nHost: a\r\n\r\n", |e| {
+                matches!(e, BadLineEnding)
+            }),
+            (b"GET / HTTP/1.1\r\nHost: a\n\r\n", |e| {
+                matches!(e, BadLineEnding)
+            }),
+            (b"GET / HTTP/1.1\r\nHost: a\r\n\n", |e| {
+                matches!(e, BadLineEnding)
+            }),
+            (
+                b"GET / HTTP/1.1\r\r\n\r\n",
+                |e| matches!(e, BadVersion(v) if v == "HTTP/1.1\r"),
+            ),
+            (
+                b"GET / HTTP/1.1\r\nHost: a\rb\r\n\r\n",
+                |e| matches!(e, MalformedHeader(h) if h == "Host: a\rb"),
+            ),
+            // obs-fold
+            (
+                b"GET / HTTP/1.1\r\nX-A: 1\r\n 2\r\n\r\n",
+                |e| matches!(e, MalformedHeader(h) if h == " 2"),
+            ),
+            (
+                b"GET / HTTP/1.1\r\n\tHost: a\r\n\r\n",
+                |e| matches!(e, MalformedHeader(h) if h == "\tHost: a"),
+            ),
+            (
+                b"GET / HTTP/1.1\r\nX: \0\r\n\r\n",
+                |e| matches!(e, MalformedHeader(h) if h == "X: \0"),
+            ),
+            (
+                b"GET / HTTP/1.1\r\nHost: a\r\nhost: b\r\n\r\n",
+                |e| matches!(e, MalformedHeader(h) if h == "host: b"),
+            ),
+        ];
+        for (payload, expected) in cases {
+            let error = request(payload).headers().await.err().unwrap();
+            assert!(expected(&error), "{}", error);
+        }
+        // bare LFs are fine once allowed
+        let payload = b"GET / HTTP/1.1\nHost: a\nX: b\r\n\n";
+        let reader = request(payload).allow_bare_lf(true);
+        assert_eq!(reader.headers_all("x").await.unwrap(), ["b"]);
+        let payload = b"POST / HTTP/1.1\nTransfer-Encoding: chunked\n\n2\nok\n0\n\n";
+        let reader = request(payload).allow_bare_lf(true);
+        assert_eq!(
+            reader.body().await.unwrap().read_to_end().await.unwrap(),
+            b"ok"
+        );
+        let payload = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nok\n0\r\n\r\n";
+        let mut body = request(payload);
+        let error = body.body().await.unwrap().read_to_end().await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        body = request(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\n\r\n");
+        assert!(body.body().await.unwrap().read_to_end().await.is_err());
+    }
+
+    /// Requests as sent by real clients, captured with `nc -l`.
+    #[tokio::test]
+    async fn test_conformance() {
+        let curl = b"GET /index.html?a=1 HTTP/1.1\r\n\
+            Host: localhost:8080\r\n\
+            User-Agent: curl/8.5.0\r\n\
+            Accept: */*\r\n\r\n";
+        let curl_post = b"POST /form HTTP/1.1\r\n\
+            Host: localhost:8080\r\n\
+            User-Agent: curl/8.5.0\r\n\
+            Accept: */*\r\n\
+            Content-Length: 7\r\n\
+            Content-Type: application/x-www-form-urlencoded\r\n\r\n\
+            a=1&b=2";
+        let curl_chunked = b"PUT /upload HTTP/1.1\r\n\
+            Host: localhost:8080\r\n\
+            User-Agent: curl/8.5.0\r\n\
+            Accept: */*\r\n\
+            Transfer-Encoding: chunked\r\n\r\n\
+            5\r\nhello\r\n0\r\n\r\n";
+        let firefox = b"GET /docs/ HTTP/1.1\r\n\
+            Host: localhost:8080\r\n\
+            User-Agent: Mozilla/5.0 (X11; Linux x86_64; rv:121.0) Gecko/20100101 Firefox/121.0\r\n\
+            Accept: text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,*/*;q=0.8\r\n\
+            Accept-Language: en-US,en;q=0.5\r\n\
+            Accept-Encoding: gzip, deflate, br\r\n\
+            Connection: keep-alive\r\n\
+            Upgrade-Insecure-Requests: 1\r\n\
+            Sec-Fetch-Dest: document\r\n\
+            Sec-Fetch-Mode: navigate\r\n\
+            Sec-Fetch-Site: none\r\n\
+            Sec-Fetch-User: ?1\r\n\r\n";
+        let chrome = b"GET /favicon.ico HTTP/1.1\r\n\
+            Host: localhost:8080\r\n\
+            Connection: keep-alive\r\n\
+            sec-ch-ua: \"Not_A Brand\";v=\"8\", \"Chromium\";v=\"120\"\r\n\
+            sec-ch-ua-mobile: ?0\r\n\
+            User-Agent: Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36\r\n\
+            sec-ch-ua-platform: \"Linux\"\r\n\
+            Accept: image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8\r\n\
+            Sec-Fetch-Site: same-origin\r\n\
+            Sec-Fetch-Mode: no-cors\r\n\
+            Sec-Fetch-Dest: image\r\n\
+            Referer: http://localhost:8080/docs/\r\n\
+            Accept-Encoding: gzip, deflate, br\r\n\
+            Accept-Language: en-US,en;q=0.9\r\n\
+            Cookie: a=1; b=2\r\n\r\n";
+        let wget = b"GET / HTTP/1.1\r\n\
+            Host: localhost:8080\r\n\
+            User-Agent: Wget/1.21.4\r\n\
+            Accept: */*\r\n\
+            Accept-Encoding: identity\r\n\
+            Connection: Keep-Alive\r\n\r\n";
+        let ab = b"HEAD / HTTP/1.0\r\n\
+            Host: localhost:8080\r\n\
+            User-Agent: ApacheBench/2.3\r\n\
+            Accept: */*\r\n\r\n";
+        // payload, method, resource, version, number of headers, body
+        type Case<'a> = (&'a [u8], &'a str, &'a str, HttpVersion, usize, &'a [u8]);
+        let cases: &[Case] = &[
+            (curl, "GET", "/index.html?a=1", HttpVersion::Http1_1, 3, b""),
+            (
+                curl_post,
+                "POST",
+                "/form",
+                HttpVersion::Http1_1,
+                5,
+                b"a=1&b=2",
+            ),
+            (
+                curl_chunked,
+                "PUT",
+                "/upload",
+                HttpVersion::Http1_1,
+                4,
+                b"hello",
+            ),
+            (firefox, "GET", "/docs/", HttpVersion::Http1_1, 11, b""),
+            (chrome, "GET", "/favicon.ico", HttpVersion::Http1_1, 14, b""),
+            (wget, "GET", "/", HttpVersion::Http1_1, 5, b""),
+            (ab, "HEAD", "/", HttpVersion::Http1_0, 3, b""),
+        ];
+        for (payload, method, resource, version, headers, body) in cases {
+            let reader = request(payload);
+            assert_eq!(reader.method().await.unwrap().as_str(), *method);
+            assert_eq!(*reader.resource().await.unwrap(), *resource);
+            assert_eq!(*reader.version().await.unwrap(), *version);
+            assert_eq!(
+                reader.header("host").await.unwrap().unwrap(),
+                "localhost:8080"
+            );
+            assert_eq!(reader.headers().await.unwrap().count(), *headers);
+            let read = reader.body().await.unwrap().read_to_end().await.unwrap();
+            assert_eq!(read, *body);
+        }
+        let reader = request(chrome);
+        assert_eq!(
+            reader.header("SEC-CH-UA").await.unwrap().unwrap(),
+            "\"Not_A Brand\";v=\"8\", \"Chromium\";v=\"120\""
+        );
+    }
+
+    fn request(payload: &[u8]) -> HttpLazyStreamReader {
+        HttpLazyStreamReader::new(Box::pin(MockRead(payload.to_vec())))
+    }
+
+    #[tokio::test]
+    async fn test_content_length_body() {
+        let reader = request(b"POST / HTTP/1.1\r\ncontent-length: 5\r\n\r\nhello, next");
+        assert_eq!(
+            reader.body().await.unwrap().read_to_end().await.unwrap(),
+            b"hello"
+        );
+        // the body was read, and the rest belongs to the next request
+        assert!(reader
+            .body()
+            .await
+            .unwrap()
+            .chunk()
+            .await
+            .unwrap()
+            .is_none());
+
+        let reader = request(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert!(reader
+            .body()
+            .await
+            .unwrap()
+            .chunk()
+            .await
+            .unwrap()
+            .is_none());
+
+        let reader = request(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort");
+        let error = reader
+            .body()
+            .await
+            .unwrap()
+            .read_to_end()
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn test_chunked_body() {
+        let payload = b"POST / HTTP/1.1\r\nTransfer-Encoding: Chunked\r\n\r\n\
+            5;name=value\r\nhello\r\n7 \r\n, world\r\n\
+            0\r\nChecksum: abc\r\nExpires: never\r\n\r\n";
+        let reader = request(payload);
+        let mut body = reader.body().await.unwrap();
+        assert_eq!(body.chunk().await.unwrap().unwrap(), b"hello");
+        assert_eq!(body.read_to_end().await.unwrap(), b", world");
+        assert_eq!(
+            body.trailers(),
+            [
+                ("Checksum".to_string(), "abc".to_string()),
+                ("Expires".to_string(), "never".to_string())
+            ]
+        );
+
+        let payload = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n";
+        let chunks: Vec<_> = request(payload)
+            .body()
+            .await
+            .unwrap()
+            .into_stream()
+            .collect()
+            .await;
+        assert_eq!(
+            chunks.into_iter().map(Result::unwrap).collect::<Vec<_>>(),
+            [b"abc"]
+        );
+
+        for invalid in [
+            &b"3\r\nabcd\r\n0\r\n\r\n"[..],
+            b"x\r\nabc\r\n0\r\n\r\n",
+            b"\r\nabc\r\n0\r\n\r\n",
+            b"fffffffffffffffff\r\n",
+            b"3\r\nabc\r\n0\r\nno colon\r\n\r\n",
+            b"3\r\nabc",
+        ] {
+            let mut payload = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+            payload.extend_from_slice(invalid);
+            let reader = request(&payload);
+            let mut body = reader.body().await.unwrap();
+            assert!(body.read_to_end().await.is_err());
+            // nothing more is read after an error
+            assert!(body.chunk().await.unwrap().is_none());
+        }
+
+        // trailers together may be as long as the headers, whatever the lines
+        let mut payload = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n".to_vec();
+        payload.extend_from_slice(&b"T: t\r\n".repeat(MAX_HEADERS / 6 + 1));
+        payload.extend_from_slice(b"\r\n");
+        let reader = request(&payload);
+        let error = reader
+            .body()
+            .await
+            .unwrap()
+            .read_to_end()
+            .await
+            .unwrap_err();
+        let error = error
+            .into_inner()
+            .unwrap()
+            .downcast::<HttpParseError>()
+            .unwrap();
+        assert!(matches!(*error, HttpParseError::HeadersTooLarge));
+    }
+
+    #[tokio::test]
+    async fn test_ambiguous_body_is_rejected() {
+        let requests = [
+            &b"POST / HTTP/1.1\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n"[..],
+            b"POST / HTTP/1.1\r\ntransfer-encoding: chunked\r\nContent-Length: 3\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: 3\r\ncontent-length: 4\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: 3, 4\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: +3\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: 99999999999999999999999\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: chunked\r\n\r\n",
+            b"POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n",
+        ];
+        for payload in &requests {
+            let reader = request(payload);
+            let error = reader.body().await.err().unwrap();
+            assert!(
+                matches!(error, HttpParseError::MalformedHeader(_)),
+                "{}",
+                error
+            );
+        }
+        // the same length twice is fine
+        let reader =
+            request(b"POST / HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 2\r\n\r\nok");
+        assert_eq!(
+            reader.body().await.unwrap().read_to_end().await.unwrap(),
+            b"ok"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_parse_errors() {
+        let long_uri = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_URI));
+        let long_header = format!(
+            "GET / HTTP/1.1\r\nA: {}\r\n\r\n",
+            "a".repeat(MAX_HEADER_LINE)
+        );
+        let many_headers = format!("GET / HTTP/1.1\r\n{}\r\n", "Accept: */*\r\n".repeat(4000));
+        use HttpParseError::*;
+        let cases: &[ErrorCase] = &[
+            (
+                b"G\0T / HTTP/1.1\r\n\r\n",
+                |e| matches!(e, BadMethod(m) if m == "G\0"),
+            ),
+            (
+                b"BR(W / HTTP/1.1\r\n\r\n",
+                |e| matches!(e, BadMethod(m) if m == "BR("),
+            ),
+            (
+                b" / HTTP/1.1\r\n\r\n",
+                |e| matches!(e, BadMethod(m) if m.is_empty()),
+            ),
+            (b"GET /a\x7fb HTTP/1.1\r\n\r\n", |e| matches!(e, BadUri)),
+            (b"GET  / HTTP/1.1\r\n\r\n", |e| matches!(e, BadUri)),
+            (b"GET /\xff HTTP/1.1\r\n\r\n", |e| matches!(e, BadUri)),
+            (long_uri.as_bytes(), |e| matches!(e, UriTooLong)),
+            (
+                b"GET / HTTP/2.0\r\n\r\n",
+                |e| matches!(e, UnsupportedVersion(v) if v == "HTTP/2.0"),
+            ),
+            (
+                b"GET / HTTPS/1.1\r\n\r\n",
+                |e| matches!(e, BadVersion(v) if v == "HTTPS/1.1"),
+            ),
+            (
+                b"GET / HTTP/1.1\r\nHost example.com\r\n\r\n",
+                |e| matches!(e, MalformedHeader(h) if h == "Host example.com"),
+            ),
+            (
+                b"GET / HTTP/1.1\r\nHost : a\r\n\r\n",
+                |e| matches!(e, MalformedHeader(h) if h == "Host : a"),
+            ),
+            (
+                b"GET / HTTP/1.1\r\nX: \xff\r\n\r\n",
+                |e| matches!(e, MalformedHeader(h) if h == "X: \u{fffd}"),
+            ),
+            (long_header.as_bytes(), |e| matches!(e, HeadersTooLarge)),
+            (many_headers.as_bytes(), |e| matches!(e, HeadersTooLarge)),
+            (b"GET / HTTP/1.1\r\nHost: a\r\n", |e| {
+                matches!(e, UnexpectedEof)
+            }),
+            (b"GET /", |e| matches!(e, UnexpectedEof)),
+        ];
+        for (payload, expected) in cases {
+            let reader = request(payload);
+            let error = reader.header("Cookie").await.err().unwrap();
+            assert!(expected(&error), "{}", error);
+            // every later part fails the same way
+            let error = reader.body().await.err().unwrap();
+            assert!(expected(&error), "{}", error);
+        }
+        // a request that failed in its headers still has its request line
+        let reader = request(b"GET / HTTP/1.1\r\nbad\r\n\r\n");
+        assert!(reader.header("Host").await.is_err());
+        assert_eq!(*reader.resource().await.unwrap(), "/");
+        assert!(reader.header("Host").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_extension_methods() {
+        for (method, expected) in [
+            ("PROPFIND", HttpMethod::Extension("PROPFIND".to_string())),
+            ("get", HttpMethod::Extension("get".to_string())),
+            ("M-SEARCH", HttpMethod::Extension("M-SEARCH".to_string())),
+            ("OPTIONS", HttpMethod::Options),
+        ] {
+            let payload = format!("{} * HTTP/1.1\r\n\r\n", method);
+            let reader = request(payload.as_bytes());
+            assert_eq!(*reader.method().await.unwrap(), expected);
+            assert_eq!(reader.method().await.unwrap().as_str(), method);
+        }
+        assert!("GET".parse::<HttpMethod>().is_ok());
+        assert_eq!(
+            "GE T".parse::<HttpMethod>().unwrap_err(),
+            "invalid method \"GE T\""
+        );
+    }
+
+    #[tokio::test]
+    async fn test_request_line_details() {
+        // empty lines before the request are skipped and later 1.x minors
+        // are read as 1.1
+        let reader = request(b"\r\n\r\nGET /a?b=c HTTP/1.7\r\n\r\n");
+        assert_eq!(*reader.method().await.unwrap(), HttpMethod::Get);
+        assert_eq!(*reader.resource().await.unwrap(), "/a?b=c");
+        assert_eq!(*reader.version().await.unwrap(), HttpVersion::Http1_1);
+        assert!(reader.header("Host").await.unwrap().is_none());
+    }
+
     macro_rules! test {
         (@test $m: ident, ($r_name:ident, $r:literal)) => {
             paste! {
@@ -330,14 +1365,14 @@ mod tests {
                 let stream = Box::pin(mock_read);
                 let reader = HttpLazyStreamReader::new(stream);
                 // read method
-                let method = reader.method().await;
+                let method = reader.method().await.unwrap();
                 assert_eq!(m_enum, *method);
-                let method = reader.method().await;
+                let method = reader.method().await.unwrap();
                 assert_eq!(m_enum, *method);
                 // read resource
-                let resource = reader.resource().await;
+                let resource = reader.resource().await.unwrap();
                 assert_eq!(*resource, *expected_resource);
-                let resource = reader.resource().await;
+                let resource = reader.resource().await.unwrap();
                 assert_eq!(*resource, *expected_resource);
             }
 
@@ -354,14 +1389,14 @@ mod tests {
                 let stream = Box::pin(mock_read);
                 let reader = HttpLazyStreamReader::new(stream);
                 // read resource
-                let resource = reader.resource().await;
+                let resource = reader.resource().await.unwrap();
                 assert_eq!(*resource, *expected_resource);
-                let resource = reader.resource().await;
+                let resource = reader.resource().await.unwrap();
                 assert_eq!(*resource, *expected_resource);
                 // read method
-                let method = reader.method().await;
+                let method = reader.method().await.unwrap();
                 assert_eq!(m_enum, *method);
-                let method = reader.method().await;
+                let method = reader.method().await.unwrap();
                 assert_eq!(m_enum, *method);
             }
         }};
@@ -382,10 +1417,184 @@ mod tests {
 
     test!({
         Resources: (home, "/home") (root, "/"),
-        Methods: (GET, POST, PUT, DELETE, HEAD),
+        Methods: (GET, POST, PUT, DELETE, HEAD, OPTIONS, PATCH, CONNECT, TRACE),
     });
 
+    #[tokio::test]
+    async fn test_keep_alive() {
+        let cases: &[(&[u8], bool)] = &[
+            (b"GET / HTTP/1.1\r\n\r\n", true),
+            (b"GET / HTTP/1.1\r\nConnection: Close\r\n\r\n", false),
+            (b"GET / HTTP/1.1\r\nConnection: TE, close\r\n\r\n", false),
+            (b"GET / HTTP/1.0\r\n\r\n", false),
+            (b"GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n", true),
+            (
+                b"GET / HTTP/1.0\r\nConnection: keep-alive\r\nConnection: close\r\n\r\n",
+                false,
+            ),
+            (b"GET / HTTP/0.9\r\nConnection: keep-alive\r\n\r\n", false),
+        ];
+        for (payload, expected) in cases {
+            let keep_alive = request(payload).keep_alive().await.unwrap();
+            assert_eq!(
+                keep_alive,
+                *expected,
+                "{}",
+                String::from_utf8_lossy(payload)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_requests() {
+        let payload = b"POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\
+            PUT /b HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n\
+            \r\nGET /c HTTP/1.1\r\nHost: c\r\n\r\n";
+        // the requests arrive in one read and the bodies are left unread
+        let reader = request(payload);
+        assert!(reader.started().await.unwrap());
+        assert_eq!(*reader.resource().await.unwrap(), "/a");
+        let reader = reader.next_request().await.unwrap().unwrap();
+        assert_eq!(*reader.resource().await.unwrap(), "/b");
+        let mut body = reader.body().await.unwrap();
+        assert_eq!(body.chunk().await.unwrap().unwrap(), b"abc");
+        let reader = reader.next_request().await.unwrap().unwrap();
+        // the empty line before the request line is skipped
+        assert_eq!(reader.method().await.unwrap().as_str(), "GET");
+        assert_eq!(reader.header("host").await.unwrap().unwrap(), "c");
+        let reader = reader.next_request().await.unwrap().unwrap();
+        assert!(!reader.started().await.unwrap());
+
+        // a large body isn't read to the end just to skip it
+        let post = |len: usize| {
+            let mut payload = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", len);
+            payload.push_str(&"a".repeat(len));
+            request(payload.as_bytes())
+        };
+        assert!(post(MAX_DISCARD + 1)
+            .next_request()
+            .await
+            .unwrap()
+            .is_none());
+        let reader = post(MAX_DISCARD).next_request().await.unwrap().unwrap();
+        assert!(!reader.started().await.unwrap());
+
+        // a request that was cut short doesn't lead to another one
+        let reader = request(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nab");
+        let error = reader.next_request().await.err().unwrap();
+        assert!(matches!(error, HttpParseError::UnexpectedEof));
+        let reader = request(b"GET / HTTP/1.1\r\nHost a\r\n\r\nGET / HTTP/1.1\r\n\r\n");
+        let error = reader.next_request().await.err().unwrap();
+        assert!(matches!(error, HttpParseError::MalformedHeader(h) if h == "Host a"));
+    }
+
     // TODO: add support for more type of tests
-    // TODO: add multi thread tests
-    // TODO: add support for incomplete streams
+
+    #[test]
+    fn test_reader_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+        fn assert_send<T: Send>(_: &T) {}
+        let reader = request(b"");
+        assert_send_sync(&reader);
+        assert_send(&reader.method());
+        assert_send(&reader.headers_all("Via"));
+        assert_send(&reader.body());
+    }
+
+    /// Writes `payload` a few bytes at a time from another task, so parts
+    /// are asked for before they arrived.
+    fn trickle(payload: Vec<u8>) -> HttpLazyStreamReader {
+        let (mut client, server) = tokio::io::duplex(64);
+        tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+            for piece in payload.chunks(7) {
+                client.write_all(piece).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+        HttpLazyStreamReader::new(Box::pin(server))
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_parts_from_many_tasks() {
+        let mut payload = b"POST /upload HTTP/1.1\r\nHost: example.com\r\n".to_vec();
+        for i in 0..20 {
+            payload.extend_from_slice(format!("X-{}: {}\r\nVia: {}\r\n", i, i, i).as_bytes());
+        }
+        payload.extend_from_slice(b"Transfer-Encoding: chunked\r\n\r\n");
+        payload.extend_from_slice(b"5\r\nhello\r\n6\r\n world\r\n0\r\nT: t\r\n\r\n");
+        let reader = Arc::new(trickle(payload));
+        let mut tasks = Vec::new();
+        for i in (0..20).rev() {
+            let reader = reader.clone();
+            tasks.push(tokio::spawn(async move {
+                let value = reader.header(&format!("x-{}", i)).await.unwrap();
+                assert_eq!(value.unwrap(), i.to_string());
+                assert_eq!(reader.method().await.unwrap().as_str(), "POST");
+                assert_eq!(*reader.resource().await.unwrap(), "/upload");
+                assert_eq!(reader.headers_all("via").await.unwrap().len(), 20);
+            }));
+        }
+        let body = {
+            let reader = reader.clone();
+            tokio::spawn(async move {
+                let mut body = reader.body().await.unwrap();
+                let read = body.read_to_end().await.unwrap();
+                (read, body.trailers())
+            })
+        };
+        for task in tasks {
+            task.await.unwrap();
+        }
+        let (read, trailers) = body.await.unwrap();
+        assert_eq!(read, b"hello world");
+        assert_eq!(trailers, [("T".to_string(), "t".to_string())]);
+        assert_eq!(reader.headers().await.unwrap().count(), 42);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_readers_on_many_threads() {
+        let tasks: Vec<_> = (0..64)
+            .map(|i| {
+                tokio::spawn(async move {
+                    let payload = format!(
+                        "GET /{} HTTP/1.1\r\nHost: {}.example.com\r\nContent-Length: 2\r\n\r\n{:02}",
+                        i, i, i
+                    );
+                    let reader = trickle(payload.into_bytes());
+                    let host = reader.header("host").await.unwrap().unwrap();
+                    assert_eq!(host, format!("{}.example.com", i));
+                    assert_eq!(*reader.resource().await.unwrap(), format!("/{}", i));
+                    let body = reader.body().await.unwrap().read_to_end().await.unwrap();
+                    assert_eq!(body, format!("{:02}", i).as_bytes());
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_errors_reach_every_task() {
+        let reader = Arc::new(trickle(b"GET / HTTP/1.1\r\nHost a\r\n\r\n".to_vec()));
+        let tasks: Vec<_> = (0..8)
+            .map(|i| {
+                let reader = reader.clone();
+                tokio::spawn(async move {
+                    let error = match i % 2 {
+                        0 => reader.header("Accept").await.err(),
+                        _ => reader.body().await.err(),
+                    };
+                    error.unwrap()
+                })
+            })
+            .collect();
+        for task in tasks {
+            let error = task.await.unwrap();
+            assert!(matches!(error, HttpParseError::MalformedHeader(h) if h == "Host a"));
+        }
+        // the request line was fine
+        assert_eq!(*reader.method().await.unwrap(), HttpMethod::Get);
+    }
 }
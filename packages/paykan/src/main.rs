@@ -1,31 +1,114 @@
+pub mod cli;
 pub mod config;
 pub mod http_server;
 pub mod lazy_stream_reader;
+pub mod response_writer;
+pub mod static_files;
+pub mod variables;
 
-use crate::config::Config;
+use crate::cli::Mode;
+use crate::http_server::Connections;
 use futures::future::join_all;
+use std::{process::exit, sync::Arc};
+use tokio::signal::{
+    ctrl_c,
+    unix::{signal, SignalKind},
+};
 
 #[tokio::main]
 async fn main() {
-    let config = Config::from(parser::parse(
-        r#"
-    http {
-        server {
-            server_name "server_name";
-            listen 127.0.0.1:8080;
+    let options = match cli::parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(error) => {
+            eprintln!("paykan: {}\n\n{}", error, cli::USAGE);
+            exit(1);
         }
-        server {
-            server_name "server_name2";
-            listen 127.0.0.1:8081;
+    };
+    if options.mode == Mode::Help {
+        println!("{}", cli::USAGE);
+        return;
+    }
+    let path = options.config.display();
+    let (block, config) = match config::load(&options.config) {
+        Ok(loaded) => loaded,
+        Err(error) => {
+            eprintln!("{}", error);
+            if options.mode != Mode::Serve {
+                eprintln!("paykan: configuration file {} test failed", path);
+            }
+            exit(1);
+        }
+    };
+    match options.mode {
+        Mode::Serve => {}
+        Mode::Test | Mode::Dump => {
+            eprintln!("paykan: the configuration file {} syntax is ok", path);
+            eprintln!("paykan: configuration file {} test is successful", path);
+            if options.mode == Mode::Dump {
+                print!("# configuration file {}:\n{}", path, block);
+            }
+            return;
+        }
+        Mode::Help => unreachable!(),
+    }
+    // the configuration is used until the process exits, by the tasks of
+    // every connection
+    let config: &'static config::Config = Box::leak(Box::new(config));
+    let http = &config.http;
+    let connections = Arc::new(Connections::new(config.events.worker_connections.get()));
+    // a socket that fails, e.g. because its address is in use, shuts the
+    // others down too
+    let sockets = http_server::sockets(&http.listeners)
+        .into_iter()
+        .map(|listeners| {
+            let connections = connections.clone();
+            async move {
+                let address = listeners[0].address;
+                let result =
+                    http_server::serve(listeners, &http.servers, connections.clone()).await;
+                if let Err(error) = &result {
+                    eprintln!("paykan: {}: {}", address, error);
+                    connections.shutdown();
+                }
+                result.is_ok()
+            }
+        });
+
+    tokio::spawn({
+        let connections = connections.clone();
+        async move {
+            stop_signal().await;
+            println!(
+                "shutting down, waiting for {} connections",
+                connections.active()
+            );
+            connections.shutdown();
         }
+    });
+    let served = join_all(sockets).await;
+    // a second signal doesn't wait for the connections that are left
+    tokio::select! {
+        _ = connections.drained() => {}
+        _ = stop_signal() => println!("exiting with {} connections open", connections.active()),
+    }
+    println!(
+        "accepted {} connections, handled {}",
+        connections.accepted(),
+        connections.handled()
+    );
+    if served.contains(&false) {
+        exit(1);
     }
-    "#,
-    ));
-    let servers = config
-        .http
-        .servers
-        .iter()
-        .map(|server| http_server::serve(server));
+}
 
-    join_all(servers).await;
+/// Resolves on SIGINT or SIGTERM.
+async fn stop_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(_) => return ctrl_c().await.unwrap_or_default(),
+    };
+    tokio::select! {
+        _ = ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
 }
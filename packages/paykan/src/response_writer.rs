@@ -0,0 +1,478 @@
+//! Writing responses over the write half of a connection: the status line,
+//! the headers and a body framed by `Content-Length`, by chunked transfer
+//! encoding or, for HTTP/1.0 clients, by closing the connection.
+use crate::lazy_stream_reader::{HttpMethod, HttpVersion};
+use std::{
+    fmt, io,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct StatusCode(pub u16);
+
+impl StatusCode {
+    pub const OK: StatusCode = StatusCode(200);
+    pub const NO_CONTENT: StatusCode = StatusCode(204);
+    pub const MOVED_PERMANENTLY: StatusCode = StatusCode(301);
+    pub const FOUND: StatusCode = StatusCode(302);
+    pub const NOT_MODIFIED: StatusCode = StatusCode(304);
+    pub const BAD_REQUEST: StatusCode = StatusCode(400);
+    pub const FORBIDDEN: StatusCode = StatusCode(403);
+    pub const NOT_FOUND: StatusCode = StatusCode(404);
+    pub const METHOD_NOT_ALLOWED: StatusCode = StatusCode(405);
+    pub const REQUEST_TIMEOUT: StatusCode = StatusCode(408);
+    pub const LENGTH_REQUIRED: StatusCode = StatusCode(411);
+    pub const PAYLOAD_TOO_LARGE: StatusCode = StatusCode(413);
+    pub const URI_TOO_LONG: StatusCode = StatusCode(414);
+    pub const HEADER_FIELDS_TOO_LARGE: StatusCode = StatusCode(431);
+    pub const INTERNAL_SERVER_ERROR: StatusCode = StatusCode(500);
+    pub const NOT_IMPLEMENTED: StatusCode = StatusCode(501);
+    pub const SERVICE_UNAVAILABLE: StatusCode = StatusCode(503);
+    pub const HTTP_VERSION_NOT_SUPPORTED: StatusCode = StatusCode(505);
+
+    pub fn reason(self) -> &'static str {
+        match self.0 {
+            200 => "OK",
+            204 => "No Content",
+            301 => "Moved Permanently",
+            302 => "Found",
+            304 => "Not Modified",
+            400 => "Bad Request",
+            403 => "Forbidden",
+            404 => "Not Found",
+            // what nginx calls it
+            405 => "Not Allowed",
+            408 => "Request Time-out",
+            411 => "Length Required",
+            413 => "Request Entity Too Large",
+            414 => "Request-URI Too Large",
+            431 => "Request Header Fields Too Large",
+            500 => "Internal Server Error",
+            501 => "Not Implemented",
+            503 => "Service Temporarily Unavailable",
+            505 => "HTTP Version Not Supported",
+            _ => match self.0 / 100 {
+                1 => "Informational",
+                2 => "Success",
+                3 => "Redirection",
+                4 => "Client Error",
+                _ => "Server Error",
+            },
+        }
+    }
+
+    /// 1xx, 204 and 304 responses never have a body.
+    pub fn allows_body(self) -> bool {
+        !matches!(self.0, 100..=199 | 204 | 304)
+    }
+}
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.0, self.reason())
+    }
+}
+
+/// How the body that follows the headers is framed.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Body {
+    /// No body, sent as `Content-Length: 0`.
+    Empty,
+    Length(u64),
+    /// The length isn't known up front. HTTP/1.0 clients don't understand
+    /// chunks, so for them the body ends when the connection is closed.
+    Chunked,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum State {
+    Head,
+    Length(u64),
+    Chunked,
+    /// Until the connection is closed.
+    Close,
+    Finished,
+}
+
+fn invalid_input(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// A response to a request of `version`. Set the status and headers, send
+/// the head with [`send_head`](Self::send_head), write the body and
+/// [`finish`](Self::finish) it. `Content-Length`, `Transfer-Encoding`,
+/// `Connection`, `Date` and `Server` are added by the writer.
+pub struct HttpResponseWriter<W> {
+    stream: W,
+    version: HttpVersion,
+    /// A response to `HEAD`, which has the headers of a `GET` but no body, or
+    /// one with a status that can't have a body.
+    head_only: bool,
+    keep_alive: bool,
+    status: StatusCode,
+    headers: Vec<(String, String)>,
+    state: State,
+}
+
+impl<W: AsyncWrite + Unpin> HttpResponseWriter<W> {
+    /// HTTP/1.1 connections are kept alive by default and HTTP/1.0 ones are
+    /// closed, see [`set_keep_alive`](Self::set_keep_alive).
+    pub fn new(stream: W, version: HttpVersion, method: &HttpMethod) -> Self {
+        Self {
+            stream,
+            version,
+            head_only: *method == HttpMethod::Head,
+            keep_alive: version >= HttpVersion::Http1_1,
+            status: StatusCode::OK,
+            headers: Vec::new(),
+            state: State::Head,
+        }
+    }
+
+    pub fn status(&mut self, status: StatusCode) -> &mut Self {
+        self.status = status;
+        self
+    }
+
+    /// The status set last, the one sent once the head is written.
+    pub fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn header(&mut self, name: impl Into<String>, value: impl Into<String>) -> &mut Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn set_keep_alive(&mut self, keep_alive: bool) -> &mut Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// Whether the connection can be used for another request once the
+    /// response is finished. HTTP/0.9 and bodies that end by closing the
+    /// connection turn this off.
+    pub fn keep_alive(&self) -> bool {
+        self.keep_alive
+    }
+
+    /// Writes the status line and the headers. HTTP/0.9 responses have no
+    /// head, just the body.
+    pub async fn send_head(&mut self, body: Body) -> io::Result<()> {
+        if self.state != State::Head {
+            return Err(invalid_input("the response head was already sent"));
+        }
+        let bodiless = !self.status.allows_body();
+        if bodiless {
+            // the body is discarded as for `HEAD`, without framing headers
+            self.head_only = true;
+        }
+        let chunked = self.version >= HttpVersion::Http1_1;
+        self.state = match body {
+            Body::Empty => State::Length(0),
+            Body::Length(len) => State::Length(len),
+            Body::Chunked if chunked || bodiless => State::Chunked,
+            Body::Chunked => State::Close,
+        };
+        if self.version == HttpVersion::Http0_9 || self.state == State::Close {
+            self.keep_alive = false;
+        }
+        if self.version == HttpVersion::Http0_9 {
+            self.state = State::Close;
+            return Ok(());
+        }
+        let mut head = format!("HTTP/1.1 {}\r\nServer: paykan\r\n", self.status);
+        head += &format!("Date: {}\r\n", http_date(SystemTime::now()));
+        for (name, value) in &self.headers {
+            let valid = |text: &str| !text.contains(['\r', '\n']);
+            if name.is_empty() || !valid(name) || !valid(value) {
+                return Err(invalid_input("invalid response header"));
+            }
+            head += &format!("{}: {}\r\n", name, value);
+        }
+        match self.state {
+            State::Length(len) if !bodiless => head += &format!("Content-Length: {}\r\n", len),
+            State::Chunked if !bodiless => head += "Transfer-Encoding: chunked\r\n",
+            _ => {}
+        }
+        head += if self.keep_alive {
+            "Connection: keep-alive\r\n\r\n"
+        } else {
+            "Connection: close\r\n\r\n"
+        };
+        self.stream.write_all(head.as_bytes()).await
+    }
+
+    /// Writes a part of the body, sending the head first with a chunked body
+    /// if it wasn't sent yet.
+    pub async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.state == State::Head {
+            self.send_head(Body::Chunked).await?;
+        }
+        match self.state {
+            State::Length(remaining) => {
+                if data.len() as u64 > remaining {
+                    return Err(invalid_input("the body is longer than its Content-Length"));
+                }
+                self.state = State::Length(remaining - data.len() as u64);
+                if !self.head_only {
+                    self.stream.write_all(data).await?;
+                }
+            }
+            // an empty chunk would end the body
+            State::Chunked if self.head_only || data.is_empty() => {}
+            State::Chunked => {
+                self.stream
+                    .write_all(format!("{:x}\r\n", data.len()).as_bytes())
+                    .await?;
+                self.stream.write_all(data).await?;
+                self.stream.write_all(b"\r\n").await?;
+            }
+            State::Close if self.head_only => {}
+            State::Close => self.stream.write_all(data).await?,
+            State::Head | State::Finished => {
+                return Err(invalid_input("the response is already finished"))
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes everything `reader` has as the body and returns its length. A
+    /// response to `HEAD` doesn't read from it.
+    pub async fn copy<R: AsyncRead + Unpin>(&mut self, reader: &mut R) -> io::Result<u64> {
+        if self.head_only {
+            return Ok(0);
+        }
+        let mut buffer = vec![0u8; 16 * 1024];
+        let mut total = 0;
+        loop {
+            let n = reader.read(&mut buffer).await?;
+            if n == 0 {
+                return Ok(total);
+            }
+            self.write(&buffer[..n]).await?;
+            total += n as u64;
+        }
+    }
+
+    /// Ends the body, sending the head with an empty body if it wasn't sent,
+    /// and closes the connection unless it's kept alive.
+    pub async fn finish(&mut self) -> io::Result<()> {
+        match self.state {
+            State::Head => self.send_head(Body::Empty).await?,
+            State::Finished => return Ok(()),
+            _ => {}
+        }
+        let result = match self.state {
+            State::Length(remaining) if remaining > 0 && !self.head_only => {
+                // the client would wait for the rest, so it can't be reused
+                self.keep_alive = false;
+                Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "the body is shorter than its Content-Length",
+                ))
+            }
+            State::Chunked if !self.head_only => self.stream.write_all(b"0\r\n\r\n").await,
+            _ => Ok(()),
+        };
+        self.state = State::Finished;
+        self.stream.flush().await?;
+        if !self.keep_alive {
+            self.stream.shutdown().await?;
+        }
+        result
+    }
+
+    /// Sends the whole response with `body`.
+    pub async fn send(&mut self, body: &[u8]) -> io::Result<()> {
+        self.send_head(Body::Length(body.len() as u64)).await?;
+        self.write(body).await?;
+        self.finish().await
+    }
+
+    pub fn into_inner(self) -> W {
+        self.stream
+    }
+}
+
+/// Formats `time` as an HTTP date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn http_date(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    let days = seconds / 86400;
+    let (hour, minute, second) = (seconds % 86400 / 3600, seconds % 3600 / 60, seconds % 60);
+    // the civil from days algorithm, with days counted from 0000-03-01
+    let days_since_march = days as i64 + 719_468;
+    let era = days_since_march / 146_097;
+    let day_of_era = days_since_march - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        hour,
+        minute,
+        second
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// The response without its `Date` header, which changes.
+    fn written(response: HttpResponseWriter<Vec<u8>>) -> String {
+        String::from_utf8(response.into_inner())
+            .unwrap()
+            .split_inclusive("\r\n")
+            .filter(|line| !line.starts_with("Date: "))
+            .collect()
+    }
+
+    fn writer(version: HttpVersion, method: HttpMethod) -> HttpResponseWriter<Vec<u8>> {
+        HttpResponseWriter::new(Vec::new(), version, &method)
+    }
+
+    #[test]
+    fn formats_dates() {
+        assert_eq!(http_date(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+        let date = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        assert_eq!(http_date(date), "Sun, 06 Nov 1994 08:49:37 GMT");
+        let date = UNIX_EPOCH + Duration::from_secs(1_709_210_096);
+        assert_eq!(http_date(date), "Thu, 29 Feb 2024 12:34:56 GMT");
+    }
+
+    #[tokio::test]
+    async fn writes_content_length() {
+        let mut response = writer(HttpVersion::Http1_1, HttpMethod::Get);
+        response
+            .status(StatusCode::NOT_FOUND)
+            .header("Content-Type", "text/plain");
+        response.send(b"missing").await.unwrap();
+        assert!(response.keep_alive());
+        assert_eq!(
+            written(response),
+            "HTTP/1.1 404 Not Found\r\nServer: paykan\r\nContent-Type: text/plain\r\n\
+             Content-Length: 7\r\nConnection: keep-alive\r\n\r\nmissing"
+        );
+
+        let mut response = writer(HttpVersion::Http1_1, HttpMethod::Get);
+        response.send_head(Body::Length(4)).await.unwrap();
+        response.write(b"ab").await.unwrap();
+        assert!(response.write(b"cde").await.is_err());
+        assert!(response.finish().await.is_err());
+        assert!(!response.keep_alive());
+    }
+
+    #[tokio::test]
+    async fn writes_chunks() {
+        let mut response = writer(HttpVersion::Http1_1, HttpMethod::Get);
+        response.write(b"hello ").await.unwrap();
+        response.write(b"").await.unwrap();
+        response.copy(&mut &b"world, and more"[..]).await.unwrap();
+        response.finish().await.unwrap();
+        assert_eq!(
+            written(response),
+            "HTTP/1.1 200 OK\r\nServer: paykan\r\nTransfer-Encoding: chunked\r\n\
+             Connection: keep-alive\r\n\r\n6\r\nhello \r\nf\r\nworld, and more\r\n0\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn suppresses_head_bodies() {
+        let mut response = writer(HttpVersion::Http1_1, HttpMethod::Head);
+        response.send(b"not sent").await.unwrap();
+        assert_eq!(
+            written(response),
+            "HTTP/1.1 200 OK\r\nServer: paykan\r\nContent-Length: 8\r\n\
+             Connection: keep-alive\r\n\r\n"
+        );
+
+        let mut response = writer(HttpVersion::Http1_1, HttpMethod::Head);
+        response.write(b"not sent").await.unwrap();
+        response.finish().await.unwrap();
+        assert_eq!(
+            written(response),
+            "HTTP/1.1 200 OK\r\nServer: paykan\r\nTransfer-Encoding: chunked\r\n\
+             Connection: keep-alive\r\n\r\n"
+        );
+
+        let mut response = writer(HttpVersion::Http1_1, HttpMethod::Get);
+        response.status(StatusCode::NOT_MODIFIED);
+        response.send(b"not sent").await.unwrap();
+        assert!(response.keep_alive());
+        assert_eq!(
+            written(response),
+            "HTTP/1.1 304 Not Modified\r\nServer: paykan\r\nConnection: keep-alive\r\n\r\n"
+        );
+
+        // not even HTTP/1.0 has to close for a streamed body that isn't sent
+        let mut response = writer(HttpVersion::Http1_0, HttpMethod::Get);
+        response.status(StatusCode::NO_CONTENT).set_keep_alive(true);
+        response.write(b"not sent").await.unwrap();
+        response.finish().await.unwrap();
+        assert!(response.keep_alive());
+        assert_eq!(
+            written(response),
+            "HTTP/1.1 204 No Content\r\nServer: paykan\r\nConnection: keep-alive\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn follows_version_defaults() {
+        // HTTP/1.0 closes by default and can't take chunks
+        let mut response = writer(HttpVersion::Http1_0, HttpMethod::Get);
+        response.send(b"ok").await.unwrap();
+        assert!(!response.keep_alive());
+        assert_eq!(
+            written(response),
+            "HTTP/1.1 200 OK\r\nServer: paykan\r\nContent-Length: 2\r\n\
+             Connection: close\r\n\r\nok"
+        );
+
+        let mut response = writer(HttpVersion::Http1_0, HttpMethod::Get);
+        response.set_keep_alive(true);
+        response.write(b"streamed").await.unwrap();
+        response.finish().await.unwrap();
+        assert!(!response.keep_alive());
+        assert_eq!(
+            written(response),
+            "HTTP/1.1 200 OK\r\nServer: paykan\r\nConnection: close\r\n\r\nstreamed"
+        );
+
+        let mut response = writer(HttpVersion::Http1_1, HttpMethod::Get);
+        response.set_keep_alive(false).send(b"").await.unwrap();
+        assert_eq!(
+            written(response),
+            "HTTP/1.1 200 OK\r\nServer: paykan\r\nContent-Length: 0\r\n\
+             Connection: close\r\n\r\n"
+        );
+
+        let mut response = writer(HttpVersion::Http0_9, HttpMethod::Get);
+        response.send(b"<html>").await.unwrap();
+        assert!(!response.keep_alive());
+        assert_eq!(written(response), "<html>");
+    }
+
+    #[tokio::test]
+    async fn rejects_header_injection() {
+        let mut response = writer(HttpVersion::Http1_1, HttpMethod::Get);
+        response.header("Location", "/a\r\nSet-Cookie: a=b");
+        assert!(response.send_head(Body::Empty).await.is_err());
+    }
+}
@@ -0,0 +1,350 @@
+//! Maps the uri of a request to a file with `root`, `alias` and `index`.
+//! The uri is decoded and its `.` and `..` segments are resolved before it's
+//! matched against locations, so nothing outside the root can be reached,
+//! also not with encoded slashes like `..%2F..%2Fetc`.
+use crate::config::{DocumentRoot, Files, Location, LocationPath};
+use crate::response_writer::StatusCode;
+use crate::variables::RequestContext;
+use std::{
+    io,
+    path::{Component, Path, PathBuf},
+    time::SystemTime,
+};
+use tokio::fs;
+
+/// What a request for a file results in.
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    File {
+        path: PathBuf,
+        len: u64,
+        modified: Option<SystemTime>,
+    },
+    /// The uri names a directory but doesn't end with `/`, redirect to the
+    /// uri with the slash.
+    Redirect(String),
+    /// An error status, 403 or 404.
+    Error(StatusCode),
+}
+
+fn hex(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|digit| digit as u8)
+}
+
+/// Decodes `%XX` escapes in `path`, resolves `.` and `..` and drops empty
+/// segments. `None` if the path doesn't start with `/`, has an invalid
+/// escape or a NUL, or its `..` segments go above `/`; all of those are
+/// answered with 400.
+pub fn normalize_uri(path: &str) -> Option<String> {
+    if !path.starts_with('/') {
+        return None;
+    }
+    let mut decoded = Vec::with_capacity(path.len());
+    let mut bytes = path.bytes();
+    while let Some(byte) = bytes.next() {
+        let byte = match byte {
+            b'%' => hex(bytes.next()?)? << 4 | hex(bytes.next()?)?,
+            byte => byte,
+        };
+        if byte == 0 {
+            return None;
+        }
+        decoded.push(byte);
+    }
+    let decoded = String::from_utf8(decoded).ok()?;
+    let mut segments: Vec<&str> = Vec::new();
+    let mut directory = false;
+    for segment in decoded.split('/') {
+        directory = true;
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
+            }
+            segment => {
+                segments.push(segment);
+                directory = false;
+            }
+        }
+    }
+    let mut uri = String::with_capacity(decoded.len());
+    for segment in &segments {
+        uri.push('/');
+        uri.push_str(segment);
+    }
+    if directory {
+        uri.push('/');
+    }
+    Some(uri)
+}
+
+/// The status for an error opening a file.
+pub fn status(error: &io::Error) -> StatusCode {
+    match error.kind() {
+        io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+        io::ErrorKind::NotFound | io::ErrorKind::NotADirectory => StatusCode::NOT_FOUND,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Escapes what can't be sent as is in a `Location` header, the uri was
+/// decoded by [`normalize_uri`].
+fn escape(uri: &str) -> String {
+    let mut escaped = String::with_capacity(uri.len());
+    for byte in uri.bytes() {
+        match byte {
+            b'%' | b'?' | b'#' | b'"' | b'<' | b'>' | b'\\' | b'^' | b'`' | b'{' | b'|' | b'}' => {
+                escaped.push_str(&format!("%{:02X}", byte))
+            }
+            byte if byte <= b' ' || byte >= 0x7f => escaped.push_str(&format!("%{:02X}", byte)),
+            byte => escaped.push(byte as char),
+        }
+    }
+    escaped
+}
+
+/// Whether a directory or file below `root` on the way to `path` is a
+/// symbolic link. The root itself may be one.
+async fn has_symlink(root: &Path, path: &Path) -> io::Result<bool> {
+    let relative = match path.strip_prefix(root) {
+        Ok(relative) => relative,
+        Err(_) => return Ok(false),
+    };
+    let mut current = root.to_path_buf();
+    for component in relative.components() {
+        current.push(component);
+        if fs::symlink_metadata(&current)
+            .await?
+            .file_type()
+            .is_symlink()
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+async fn file(files: &Files, root: &Path, path: PathBuf) -> Result<Outcome, io::Error> {
+    if files.disable_symlinks && has_symlink(root, &path).await? {
+        return Ok(Outcome::Error(StatusCode::FORBIDDEN));
+    }
+    let metadata = fs::metadata(&path).await?;
+    if metadata.is_dir() {
+        return Err(io::ErrorKind::IsADirectory.into());
+    }
+    Ok(Outcome::File {
+        path,
+        len: metadata.len(),
+        modified: metadata.modified().ok(),
+    })
+}
+
+/// Finds the file for the normalized `uri` in `location`, or in the server
+/// when no location matched. `query` is kept when redirecting.
+pub async fn resolve(
+    files: &Files,
+    location: Option<&Location>,
+    uri: &str,
+    query: Option<&str>,
+    context: &RequestContext<'_>,
+) -> Outcome {
+    let (root, relative) = match &files.root {
+        DocumentRoot::Root(root) => (root.evaluate(context).await, uri),
+        DocumentRoot::Alias(alias) => {
+            let prefix = match location.map(|location| &location.path) {
+                Some(LocationPath::Prefix { prefix, .. }) | Some(LocationPath::Exact(prefix)) => {
+                    prefix.as_str()
+                }
+                _ => "",
+            };
+            let relative = &uri[prefix.len().min(uri.len())..];
+            // `location /images` also matches `/images../secret`, the alias
+            // only replaces whole segments
+            if !prefix.ends_with('/') && !relative.is_empty() && !relative.starts_with('/') {
+                return Outcome::Error(StatusCode::NOT_FOUND);
+            }
+            (alias.evaluate(context).await, relative)
+        }
+    };
+    let relative = Path::new(relative.trim_start_matches('/'));
+    // the uri is normalized, but nothing may lead out of the root regardless
+    if !relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Outcome::Error(StatusCode::NOT_FOUND);
+    }
+    let root = PathBuf::from(root);
+    let path = root.join(relative);
+    if uri.ends_with('/') {
+        for index in &files.index {
+            match file(files, &root, path.join(index)).await {
+                Ok(outcome) => return outcome,
+                Err(error)
+                    if status(&error) == StatusCode::NOT_FOUND
+                        || error.kind() == io::ErrorKind::IsADirectory => {}
+                Err(error) => return Outcome::Error(status(&error)),
+            }
+        }
+        // a directory without an index file isn't listed
+        return match fs::metadata(&path).await {
+            Ok(metadata) if metadata.is_dir() => Outcome::Error(StatusCode::FORBIDDEN),
+            Ok(_) => Outcome::Error(StatusCode::NOT_FOUND),
+            Err(error) => Outcome::Error(status(&error)),
+        };
+    }
+    match file(files, &root, path).await {
+        Ok(outcome) => outcome,
+        Err(error) if error.kind() == io::ErrorKind::IsADirectory => {
+            let mut target = escape(uri);
+            target.push('/');
+            if let Some(query) = query {
+                target.push('?');
+                target.push_str(query);
+            }
+            Outcome::Redirect(target)
+        }
+        Err(error) => Outcome::Error(status(&error)),
+    }
+}
+
+/// The `Content-Type` for a file, by its extension.
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    match extension.as_str() {
+        "html" | "htm" | "shtml" => "text/html",
+        "css" => "text/css",
+        "xml" => "text/xml",
+        "txt" => "text/plain",
+        "js" | "mjs" => "application/javascript",
+        "json" => "application/json",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        "zip" => "application/zip",
+        "gif" => "image/gif",
+        "jpeg" | "jpg" => "image/jpeg",
+        "png" => "image/png",
+        "svg" | "svgz" => "image/svg+xml",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "mp3" => "audio/mpeg",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, Server};
+    use crate::lazy_stream_reader::HttpLazyStreamReader;
+    use std::{convert::TryFrom, io::Cursor};
+
+    #[test]
+    fn normalizes_uris() {
+        let cases = [
+            ("/", Some("/")),
+            ("/a/b", Some("/a/b")),
+            ("/a//b/./c/", Some("/a/b/c/")),
+            ("/a/b/..", Some("/a/")),
+            ("/a/%62%2Fc", Some("/a/b/c")),
+            ("/a/../../etc/passwd", None),
+            ("/..%2F..%2Fetc/passwd", None),
+            ("/a/%2e%2e/%2E%2E/etc", None),
+            ("/%41%zz", None),
+            ("/a%", None),
+            ("/a%00b", None),
+            ("a/b", None),
+        ];
+        for (path, expected) in &cases {
+            assert_eq!(normalize_uri(path).as_deref(), *expected, "{}", path);
+        }
+    }
+
+    #[test]
+    fn guesses_content_types() {
+        assert_eq!(content_type(Path::new("/a/index.HTML")), "text/html");
+        assert_eq!(
+            content_type(Path::new("a.tar.gz")),
+            "application/octet-stream"
+        );
+        assert_eq!(
+            content_type(Path::new("README")),
+            "application/octet-stream"
+        );
+    }
+
+    #[tokio::test]
+    async fn resolves_files() {
+        let dir = std::env::temp_dir().join(format!("paykan-static-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        for path in &["www/docs", "www/empty", "data/img", "secret"] {
+            std::fs::create_dir_all(dir.join(path)).unwrap();
+        }
+        for (path, body) in &[
+            ("www/index.html", "home"),
+            ("www/docs/a.txt", "a"),
+            ("www/docs/index.htm", "docs"),
+            ("data/img/b.png", "b"),
+            ("secret/key", "key"),
+        ] {
+            std::fs::write(dir.join(path), body).unwrap();
+        }
+        std::os::unix::fs::symlink(dir.join("secret"), dir.join("www/link")).unwrap();
+        let source = format!(
+            "http {{ server {{
+                root {dir}/www;
+                index index.html index.htm;
+                location /images/ {{ alias {dir}/data/img/; }}
+                location /pics {{ alias {dir}/data/img/; }}
+                location /www/ {{ root {dir}; disable_symlinks on; }}
+            }} }}",
+            dir = dir.display()
+        );
+        let config = Config::try_from(parser::parse(&source).unwrap()).unwrap();
+        let server: &Server = &config.http.servers[0];
+        let reader = HttpLazyStreamReader::new(Box::pin(Cursor::new(Vec::new())));
+        let context = RequestContext {
+            reader: &reader,
+            server,
+            local_addr: "127.0.0.1:80".parse().unwrap(),
+            remote_addr: "127.0.0.1:5000".parse().unwrap(),
+        };
+        let (context, dir) = (&context, &dir);
+        let resolve = |uri: &'static str| async move {
+            let location = server.location(uri);
+            let files = location.map_or(&server.files, |l| &l.files);
+            match resolve(files, location, uri, Some("x=1"), context).await {
+                Outcome::File { path, len, .. } => {
+                    let path = path.strip_prefix(dir).unwrap().display().to_string();
+                    format!("{} {}", path, len)
+                }
+                Outcome::Redirect(target) => format!("-> {}", target),
+                Outcome::Error(status) => status.0.to_string(),
+            }
+        };
+        assert_eq!(resolve("/").await, "www/index.html 4");
+        assert_eq!(resolve("/docs/").await, "www/docs/index.htm 4");
+        assert_eq!(resolve("/docs/a.txt").await, "www/docs/a.txt 1");
+        assert_eq!(resolve("/docs").await, "-> /docs/?x=1");
+        assert_eq!(escape("/a b/%?\r\n/é"), "/a%20b/%25%3F%0D%0A/%C3%A9");
+        assert_eq!(resolve("/empty/").await, "403");
+        assert_eq!(resolve("/missing").await, "404");
+        assert_eq!(resolve("/missing/").await, "404");
+        assert_eq!(resolve("/docs/a.txt/b").await, "404");
+        assert_eq!(resolve("/images/b.png").await, "data/img/b.png 1");
+        assert_eq!(resolve("/pics/b.png").await, "data/img/b.png 1");
+        assert_eq!(resolve("/pics../secret/key").await, "404");
+        assert_eq!(resolve("/link/key").await, "www/link/key 3");
+        assert_eq!(resolve("/www/link/key").await, "403");
+        assert_eq!(resolve("/www/docs/a.txt").await, "www/docs/a.txt 1");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
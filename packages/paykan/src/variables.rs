@@ -0,0 +1,296 @@
+//! `$name` and `${name}` variables in directive parameters. Directives that
+//! accept variables parse their parameters into a [`Template`] when the
+//! config is loaded, so unknown variables are reported up front and requests
+//! only have to look values up.
+use crate::config::{server_name, Server};
+use crate::lazy_stream_reader::HttpLazyStreamReader;
+use std::{error::Error, fmt, net::SocketAddr, str::FromStr};
+
+/// Variables with a fixed name. `http_*` (request headers) and `arg_*`
+/// (query arguments) are accepted with any suffix.
+const VARIABLES: &[&str] = &[
+    "args",
+    "host",
+    "query_string",
+    "remote_addr",
+    "remote_port",
+    "request_method",
+    "request_uri",
+    "scheme",
+    "server_addr",
+    "server_name",
+    "server_port",
+    "server_protocol",
+    "uri",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment {
+    Literal(String),
+    Variable(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    pub segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VariableError {
+    /// `${` without the closing `}`.
+    UnterminatedBrace,
+    /// `${}`.
+    EmptyName,
+    Unknown(String),
+}
+
+impl fmt::Display for VariableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VariableError::UnterminatedBrace => write!(f, "missing '}}' after \"${{\""),
+            VariableError::EmptyName => write!(f, "empty variable name"),
+            VariableError::Unknown(name) => write!(f, "unknown variable \"${}\"", name),
+        }
+    }
+}
+
+impl Error for VariableError {}
+
+fn is_known(name: &str) -> bool {
+    VARIABLES.contains(&name)
+        || (name.len() > 5 && name.starts_with("http_"))
+        || (name.len() > 4 && name.starts_with("arg_"))
+}
+
+fn is_name_char(char: char) -> bool {
+    char.is_ascii_alphanumeric() || char == '_'
+}
+
+impl FromStr for Template {
+    type Err = VariableError;
+
+    /// A `$` that isn't followed by a name or `{` is kept as a literal.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut rest = value;
+        while let Some(index) = rest.find('$') {
+            literal.push_str(&rest[..index]);
+            let after = &rest[index + 1..];
+            let (name, next) = if let Some(braced) = after.strip_prefix('{') {
+                let end = braced.find('}').ok_or(VariableError::UnterminatedBrace)?;
+                (&braced[..end], &braced[end + 1..])
+            } else {
+                let end = after.find(|c| !is_name_char(c)).unwrap_or(after.len());
+                (&after[..end], &after[end..])
+            };
+            rest = next;
+            if name.is_empty() {
+                if after.starts_with('{') {
+                    return Err(VariableError::EmptyName);
+                }
+                literal.push('$');
+                continue;
+            }
+            if !is_known(name) {
+                return Err(VariableError::Unknown(name.to_string()));
+            }
+            if !literal.is_empty() {
+                segments.push(Segment::Literal(std::mem::take(&mut literal)));
+            }
+            segments.push(Segment::Variable(name.to_string()));
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Ok(Template { segments })
+    }
+}
+
+impl Template {
+    /// Whether the template has no variables and evaluates to the same string
+    /// for every request.
+    pub fn is_literal(&self) -> bool {
+        self.segments
+            .iter()
+            .all(|segment| matches!(segment, Segment::Literal(_)))
+    }
+
+    /// Resolves the variables against `request`. Variables without a value,
+    /// like a header the request didn't send, evaluate to an empty string.
+    pub async fn evaluate(&self, request: &RequestContext<'_>) -> String {
+        let mut result = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => result.push_str(literal),
+                Segment::Variable(name) => {
+                    if let Some(value) = request.variable(name).await {
+                        result.push_str(&value);
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+/// What variables are resolved against: the request being read and the
+/// connection and server it arrived on.
+pub struct RequestContext<'a> {
+    pub reader: &'a HttpLazyStreamReader,
+    pub server: &'a Server,
+    pub local_addr: SocketAddr,
+    pub remote_addr: SocketAddr,
+}
+
+impl RequestContext<'_> {
+    async fn header(&self, name: &str) -> Option<String> {
+        // `$http_user_agent` is the `User-Agent` header
+        let name = name.replace('_', "-");
+        self.reader.header(&name).await.ok()?
+    }
+
+    /// Empty if the request line couldn't be read.
+    async fn request_uri(&self) -> String {
+        self.reader.resource().await.cloned().unwrap_or_default()
+    }
+
+    async fn args(&self) -> String {
+        let uri = self.request_uri().await;
+        uri.split_once('?')
+            .map(|(_, args)| args.to_string())
+            .unwrap_or_default()
+    }
+
+    pub async fn variable(&self, name: &str) -> Option<String> {
+        let value = match name {
+            "args" | "query_string" => self.args().await,
+            "host" => match self
+                .header("host")
+                .await
+                .as_deref()
+                .and_then(server_name::normalize_host)
+            {
+                Some(host) if !host.is_empty() => host,
+                _ => self.server.name(),
+            },
+            "remote_addr" => self.remote_addr.ip().to_string(),
+            "remote_port" => self.remote_addr.port().to_string(),
+            "request_method" => self.reader.method().await.ok()?.as_str().to_string(),
+            "request_uri" => self.request_uri().await,
+            "scheme" => "http".to_string(),
+            "server_addr" => self.local_addr.ip().to_string(),
+            "server_name" => self.server.name(),
+            "server_port" => self.local_addr.port().to_string(),
+            "server_protocol" => self.reader.version().await.ok()?.as_str().to_string(),
+            "uri" => {
+                let uri = self.request_uri().await;
+                uri.split('?').next().unwrap_or_default().to_string()
+            }
+            _ => {
+                if let Some(header) = name.strip_prefix("http_") {
+                    return self.header(header).await;
+                }
+                let arg = name.strip_prefix("arg_")?;
+                let args = self.args().await;
+                return args
+                    .split('&')
+                    .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+                    .find(|(key, _)| *key == arg)
+                    .map(|(_, value)| value.to_string());
+            }
+        };
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn template(value: &str) -> Vec<Segment> {
+        Template::from_str(value).unwrap().segments
+    }
+
+    fn literal(value: &str) -> Segment {
+        Segment::Literal(value.to_string())
+    }
+
+    fn variable(name: &str) -> Segment {
+        Segment::Variable(name.to_string())
+    }
+
+    #[test]
+    fn parses_segments() {
+        assert_eq!(template("/var/www"), vec![literal("/var/www")]);
+        assert_eq!(
+            template("$scheme://$host${request_uri}x"),
+            vec![
+                variable("scheme"),
+                literal("://"),
+                variable("host"),
+                variable("request_uri"),
+                literal("x"),
+            ]
+        );
+        assert_eq!(
+            template("$http_x_forwarded_for-$arg_id"),
+            vec![
+                variable("http_x_forwarded_for"),
+                literal("-"),
+                variable("arg_id")
+            ]
+        );
+        assert_eq!(template("a$ $"), vec![literal("a$ $")]);
+        assert!(Template::from_str("/static").unwrap().is_literal());
+        assert!(!Template::from_str("$uri").unwrap().is_literal());
+    }
+
+    #[test]
+    fn rejects_invalid_variables() {
+        let error = |value| Template::from_str(value).unwrap_err();
+        assert_eq!(error("${host"), VariableError::UnterminatedBrace);
+        assert_eq!(error("${}"), VariableError::EmptyName);
+        assert_eq!(error("$hots"), VariableError::Unknown("hots".to_string()));
+        assert_eq!(error("$http_"), VariableError::Unknown("http_".to_string()));
+    }
+
+    #[tokio::test]
+    async fn evaluates_against_request() {
+        let request = "GET /a/b?id=7&x HTTP/1.1\r\nHost: Example.COM\r\nUser-Agent: curl\r\n\r\n";
+        let reader = HttpLazyStreamReader::new(Box::pin(Cursor::new(request.as_bytes().to_vec())));
+        let server = Server {
+            server_name: vec!["default".parse().unwrap()],
+            ..Server::default()
+        };
+        let context = RequestContext {
+            reader: &reader,
+            server: &server,
+            local_addr: "127.0.0.1:8080".parse().unwrap(),
+            remote_addr: "10.0.0.1:5000".parse().unwrap(),
+        };
+        let evaluate = |value: &str| {
+            let template = Template::from_str(value).unwrap();
+            let context = &context;
+            async move { template.evaluate(context).await }
+        };
+        assert_eq!(
+            evaluate("$request_method $scheme://$host$request_uri").await,
+            "GET http://example.com/a/b?id=7&x"
+        );
+        assert_eq!(
+            evaluate("$uri|$args|$arg_id|$arg_x|$server_protocol").await,
+            "/a/b|id=7&x|7||HTTP/1.1"
+        );
+        assert_eq!(
+            evaluate("$remote_addr:$remote_port -> $server_addr:$server_port").await,
+            "10.0.0.1:5000 -> 127.0.0.1:8080"
+        );
+        assert_eq!(
+            evaluate("[$http_user_agent][$server_name][$arg_missing]").await,
+            "[curl][default][]"
+        );
+    }
+}